@@ -0,0 +1,251 @@
+use anyhow::{anyhow, Result};
+use crate::audit::{AuditAction, AuditRecord};
+use crate::config::AppConfig;
+use crate::payment::adjust_balance;
+use crate::peer::Bans;
+use crate::state::AppState;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+/// Admin API requests, sent as `["ADMIN", {"method": ..., "params": {...}}]` over an
+/// authenticated websocket, or as the body of `POST /admin` with NIP-98 auth
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum AdminRequest {
+    ListConnections,
+    ListJobs,
+    CancelJob { job_id: String },
+    Kick { peer_id: usize },
+    Ban { pubkey: Option<String>, ip: Option<IpAddr> },
+    Unban { pubkey: Option<String>, ip: Option<IpAddr> },
+    ListBans,
+    /// Positive credits the account, negative debits it
+    AdjustBalance { pubkey: String, amount_sat: i64 },
+    /// Profile settings as in the config file. Settings left out keep their current value
+    SetProfile { name: String, settings: Value },
+    /// Assign a pubkey to a profile, or remove its assignment with no profile
+    AssignProfile { pubkey: String, profile: Option<String> },
+    WhitelistAdd { pubkey: String },
+    WhitelistRemove { pubkey: String },
+    SetDifficulty { min_pow_difficulty: Option<u16>, max_pow_difficulty: Option<u16> },
+}
+
+impl AdminRequest {
+    /// Whether the request changes bans, profile assignments or config overrides
+    pub fn is_change(&self) -> bool {
+        matches!(self,
+            Self::Ban { .. } | Self::Unban { .. } | Self::SetProfile { .. } | Self::AssignProfile { .. }
+            | Self::WhitelistAdd { .. } | Self::WhitelistRemove { .. } | Self::SetDifficulty { .. })
+    }
+}
+
+/// Admin API changes, as saved to the admin state file
+#[derive(Serialize, Deserialize, Default)]
+struct AdminChanges {
+    #[serde(default)]
+    bans: Bans,
+    #[serde(default)]
+    profile_assignments: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    config_overrides: Option<toml::Value>,
+}
+
+/// Bans, profile assignments and config overrides made with the admin API. Kept in
+/// memory, and saved to a JSON file when one is configured
+#[derive(Default)]
+pub struct AdminStore {
+    file: Option<PathBuf>,
+    saving: Mutex<()>,
+}
+
+impl AdminStore {
+
+    pub fn new(file: Option<&Path>) -> Self {
+        Self { file: file.map(Path::to_path_buf), saving: Mutex::new(()) }
+    }
+
+    /// Apply the changes saved in the admin state file (created on the first change)
+    pub async fn restore(&self, state: &AppState) -> Result<()> {
+        let path = match &self.file {
+            None => return Ok(()),
+            Some(path) => path,
+        };
+
+        let changes: AdminChanges = match std::fs::read_to_string(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => AdminChanges::default(),
+            Err(e) => return Err(anyhow!("Unable to read admin state {path:?}: {e}")),
+            Ok(json) => serde_json::from_str(&json).map_err(|e| anyhow!("Invalid admin state {path:?}: {e}"))?,
+        };
+
+        for pubkey in &changes.bans.pubkeys {
+            state.peers.ban(Some(pubkey), None).await;
+        }
+        for ip in &changes.bans.ips {
+            state.peers.ban(None, Some(*ip)).await;
+        }
+
+        for (pubkey, profile) in &changes.profile_assignments {
+            state.profiles.assign(pubkey, profile).await;
+        }
+
+        if let Some(overrides) = changes.config_overrides {
+            state.config.apply_override(overrides).await
+                .map_err(|e| anyhow!("Invalid admin state {path:?}: {e}"))?;
+        }
+
+        info!("Admin state: {path:?}");
+
+        Ok(())
+    }
+
+    /// Save the current admin changes, if there's an admin state file
+    pub async fn save(&self, state: &AppState) -> Result<()> {
+        let path = match &self.file {
+            None => return Ok(()),
+            Some(path) => path,
+        };
+
+        // One save at a time, so an older snapshot can't replace a newer one
+        let _saving = self.saving.lock().await;
+
+        let changes = AdminChanges {
+            bans: state.peers.bans().await,
+            profile_assignments: state.profiles.assignments().await.into_iter().collect(),
+            config_overrides: Some(state.config.overrides().await),
+        };
+
+        // Replace the file in one go, so a failed write doesn't lose every change
+        let saving_path = path.with_extension("saving");
+        std::fs::write(&saving_path, serde_json::to_string_pretty(&changes)?)
+            .and_then(|_| std::fs::rename(&saving_path, path))
+            .map_err(|e| anyhow!("Unable to save admin state {path:?}: {e}"))
+    }
+}
+
+/// Run an admin request for an authenticated pubkey, returning the result as JSON
+pub async fn handle_admin_request(state: &AppState, app_config: &AppConfig, admin_pubkey: &str, request: AdminRequest) -> Result<Value> {
+
+    if !app_config.is_admin(admin_pubkey) {
+        return Err(anyhow!("restricted: admin only"))
+    }
+
+    info!("Admin request from {admin_pubkey}: {request:?}");
+
+    let result = match run_request(state, app_config, request.clone()).await {
+        Ok(value) if request.is_change() => state.admin.save(state).await.map(|_| value),
+        result => result,
+    };
+
+    state.audit.record(AuditRecord {
+        message: Some(format!("{request:?}")),
+        pubkey: Some(admin_pubkey.to_string()),
+        ..AuditRecord::new(AuditAction::Admin, if result.is_ok() { "ok" } else { "failed" })
+    });
+
+    result
+}
+
+async fn run_request(state: &AppState, app_config: &AppConfig, request: AdminRequest) -> Result<Value> {
+    match request {
+        AdminRequest::ListConnections => Ok(json!(state.peers.list().await)),
+
+        AdminRequest::ListJobs => {
+            let mut jobs: Vec<_> = state.jobs.list().iter().map(|job| job.summary()).collect();
+            jobs.sort_by_key(|job| job.created_at);
+            Ok(json!(jobs))
+        },
+
+        AdminRequest::CancelJob { job_id } => {
+            let job = state.jobs.get(&job_id).ok_or_else(|| anyhow!("job not found"))?;
+            match job.cancel() {
+                true => Ok(json!(job.summary())),
+                false => Err(anyhow!("job already finished")),
+            }
+        },
+
+        AdminRequest::Kick { peer_id } => {
+            match state.peers.kick(peer_id).await {
+                true => Ok(json!({ "kicked": peer_id })),
+                false => Err(anyhow!("peer not connected")),
+            }
+        },
+
+        AdminRequest::Ban { pubkey, ip } => {
+            if pubkey.is_none() && ip.is_none() {
+                return Err(anyhow!("pubkey or ip is required"))
+            }
+            state.peers.ban(pubkey.as_deref(), ip).await;
+            Ok(json!(state.peers.bans().await))
+        },
+
+        AdminRequest::Unban { pubkey, ip } => {
+            state.peers.unban(pubkey.as_deref(), ip).await;
+            Ok(json!(state.peers.bans().await))
+        },
+
+        AdminRequest::ListBans => Ok(json!(state.peers.bans().await)),
+
+        AdminRequest::AdjustBalance { pubkey, amount_sat } => {
+            // Recorded as an admin request only, not as a credit or debit
+            adjust_balance(&pubkey, amount_sat).await?;
+            Ok(json!({ "pubkey": pubkey, "amount_sat": amount_sat }))
+        },
+
+        AdminRequest::SetProfile { name, settings } => {
+            let overlay = json!({ "profiles": { &name: settings } });
+            apply_override(state, overlay).await?;
+            Ok(json!(state.config.current().await.profiles.get(&name)))
+        },
+
+        AdminRequest::AssignProfile { pubkey, profile } => {
+            match profile {
+                None => { state.profiles.unassign(&pubkey).await; },
+                Some(profile) => {
+                    if !app_config.profiles.contains_key(&profile) {
+                        return Err(anyhow!("unknown profile {profile}"))
+                    }
+                    state.profiles.assign(&pubkey, &profile).await;
+                },
+            }
+            Ok(json!(state.profiles.assignments().await))
+        },
+
+        AdminRequest::WhitelistAdd { pubkey } => {
+            let mut whitelist = app_config.pubkey_whitelist.clone();
+            if !whitelist.contains(&pubkey) {
+                whitelist.push(pubkey);
+            }
+            apply_override(state, json!({ "pubkey_whitelist": whitelist })).await?;
+            Ok(json!(state.config.current().await.pubkey_whitelist))
+        },
+
+        AdminRequest::WhitelistRemove { pubkey } => {
+            let whitelist: Vec<_> = app_config.pubkey_whitelist.iter().filter(|p| **p != pubkey).collect();
+            apply_override(state, json!({ "pubkey_whitelist": whitelist })).await?;
+            Ok(json!(state.config.current().await.pubkey_whitelist))
+        },
+
+        AdminRequest::SetDifficulty { min_pow_difficulty, max_pow_difficulty } => {
+            let overlay = json!({
+                "min_pow_difficulty": min_pow_difficulty.unwrap_or(app_config.min_pow_difficulty),
+                "max_pow_difficulty": max_pow_difficulty.unwrap_or(app_config.max_pow_difficulty),
+            });
+            apply_override(state, overlay).await?;
+
+            let app_config = state.config.current().await;
+            Ok(json!({
+                "min_pow_difficulty": app_config.min_pow_difficulty,
+                "max_pow_difficulty": app_config.max_pow_difficulty,
+            }))
+        },
+    }
+}
+
+async fn apply_override(state: &AppState, overlay: Value) -> Result<()> {
+    let overlay = toml::Value::try_from(overlay).map_err(|e| anyhow!("invalid settings: {e}"))?;
+    state.config.apply_override(overlay).await
+}
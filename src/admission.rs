@@ -0,0 +1,112 @@
+//! Event admission with a nostr-rs-relay compatible gRPC server (nauthz).
+//!
+//! Each PoW request is sent as an `EventRequest` with the event, client IP,
+//! authenticated pubkey, and an origin of `pow:<difficulty>` so the server can
+//! tell PoW requests apart from relay writes. The server's reply is a contract:
+//!
+//! - deny: the request is rejected, with the reply message (if any) as the reason
+//! - permit, with no message or any other message: the requested difficulty is mined
+//! - permit, with the message `difficulty:<n>`: `<n>` is mined instead. `<n>` must be
+//!   a whole number from 0 to 65535, or the request fails. It's then kept within the
+//!   pubkey's profile range (and the calibrated max when clamping is enabled)
+
+use anyhow::{anyhow, Result};
+use nostr_rs_relay::event::Event;
+use nostr_rs_relay::nauthz::nauthz_grpc::authorization_client::AuthorizationClient;
+use nostr_rs_relay::nauthz::nauthz_grpc::event::TagEntry;
+use nostr_rs_relay::nauthz::nauthz_grpc::{Decision, Event as GrpcEvent, EventRequest};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tonic::transport::Channel;
+
+/// Prefix an admission server can put in a permit message to change the target difficulty
+pub const ADJUST_DIFFICULTY_PREFIX: &str = "difficulty:";
+
+/// Client for a nostr-rs-relay compatible gRPC event admission server (nauthz)
+pub struct AdmissionClient {
+    pub server_addr: String,
+    // Cloned for each request. Clones share the connection, so requests don't wait
+    // on each other. Unset until the server is first reached
+    client: Mutex<Option<AuthorizationClient<Channel>>>,
+}
+
+impl AdmissionClient {
+    pub async fn connect(server_addr: &str) -> Self {
+        info!("Connecting to gRPC event admission server: {server_addr}");
+
+        let admission = Self {
+            server_addr: server_addr.to_string(),
+            client: Mutex::new(None),
+        };
+
+        if let Err(e) = admission.client().await {
+            warn!("gRPC event admission server unavailable, retrying on the next request: {e:?}");
+        }
+
+        admission
+    }
+
+    async fn client(&self) -> Result<AuthorizationClient<Channel>> {
+        if let Some(client) = self.client.lock().expect("admission client lock poisoned").clone() {
+            return Ok(client)
+        }
+
+        let client = AuthorizationClient::connect(self.server_addr.clone()).await?;
+        *self.client.lock().expect("admission client lock poisoned") = Some(client.clone());
+
+        Ok(client)
+    }
+
+    /// Returns the target difficulty to mine, or why the request was denied
+    pub async fn admit(&self, event: &Event, pubkey: &str, real_ip: Option<IpAddr>, target_difficulty: u16) -> Result<u16> {
+
+        let request = EventRequest {
+            event: Some(grpc_event(event)),
+            ip_addr: Some(real_ip.map(|ip| ip.to_string()).unwrap_or_default()),
+            origin: Some(format!("pow:{target_difficulty}")),
+            user_agent: None,
+            auth_pubkey: hex::decode(pubkey).ok(),
+            nip05: None,
+        };
+
+        let unavailable = |e: anyhow::Error| {
+            error!("gRPC event admission failed for {}: {e:?}", event.id);
+            anyhow!("error: admission check unavailable")
+        };
+
+        let mut client = self.client().await.map_err(unavailable)?;
+        let reply = client.event_admit(request).await
+            .map_err(|e| unavailable(e.into()))?
+            .into_inner();
+
+        if reply.decision != Decision::Permit as i32 {
+            return Err(match reply.message {
+                Some(message) if !message.is_empty() => anyhow!("blocked: {message}"),
+                _ => anyhow!("blocked: rejected by admission server"),
+            })
+        }
+
+        match reply.message.as_deref().and_then(|m| m.strip_prefix(ADJUST_DIFFICULTY_PREFIX)) {
+            None => Ok(target_difficulty),
+            Some(difficulty) => {
+                let adjusted = difficulty.trim().parse::<u16>()
+                    .map_err(|_| anyhow!("error: invalid difficulty from admission server"))?;
+
+                info!("Admission server adjusted {} difficulty from {target_difficulty} to {adjusted}", event.id);
+                Ok(adjusted)
+            },
+        }
+    }
+}
+
+fn grpc_event(event: &Event) -> GrpcEvent {
+    GrpcEvent {
+        id: hex::decode(&event.id).unwrap_or_default(),
+        pubkey: hex::decode(&event.pubkey).unwrap_or_default(),
+        created_at: event.created_at,
+        kind: event.kind,
+        content: event.content.clone(),
+        tags: event.tags.iter().map(|tag| TagEntry { values: tag.clone() }).collect(),
+        sig: hex::decode(&event.sig).unwrap_or_default(),
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::{anyhow, Result};
+use crate::config::AuditSettings;
+use crate::get_timestamp;
+use serde::Serialize;
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+// prev_hash of the first record in a hash chained log
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Quote,
+    Debit,
+    Credit,
+    JobStart,
+    JobFinish,
+    JobRejected,
+    Auth,
+    Admin,
+}
+
+/// One audit log line. Fields that don't apply to an action are left out
+#[derive(Serialize, Debug, Clone)]
+pub struct AuditRecord {
+    pub action: AuditAction,
+    /// ok, failed, or the job status / rejection reason
+    pub outcome: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub difficulty: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_sat: Option<u32>,
+}
+
+impl AuditRecord {
+    pub fn new(action: AuditAction, outcome: &str) -> Self {
+        Self {
+            action,
+            outcome: outcome.to_string(),
+            message: None,
+            pubkey: None,
+            ip: None,
+            event_id: None,
+            job_id: None,
+            difficulty: None,
+            amount_sat: None,
+        }
+    }
+}
+
+/// Append-only JSON lines audit log of payments, jobs and auth. When hash chained,
+/// each line carries the hash of the previous line, so edits and deletions are detected
+#[derive(Default)]
+pub struct AuditLog {
+    writer: Option<Mutex<AuditWriter>>,
+}
+
+struct AuditWriter {
+    file: File,
+    // None when the log isn't hash chained
+    last_hash: Option<String>,
+}
+
+impl AuditLog {
+
+    /// Open (or create) the configured audit log. Disabled when no file is configured
+    pub fn open(settings: &AuditSettings) -> Result<Self> {
+        let path = match &settings.log_file {
+            None => return Ok(Self::default()),
+            Some(path) => path,
+        };
+
+        let last_hash = match settings.hash_chain {
+            false => None,
+            true => Some(last_hash(path)?.unwrap_or_else(|| GENESIS_HASH.to_string())),
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| anyhow!("Unable to open audit log {path:?}: {e}"))?;
+
+        info!("Audit log: {path:?} (hash chain: {})", settings.hash_chain);
+
+        Ok(Self { writer: Some(Mutex::new(AuditWriter { file, last_hash })) })
+    }
+
+    pub fn record(&self, record: AuditRecord) {
+        let writer = match &self.writer {
+            None => return,
+            Some(writer) => writer,
+        };
+
+        let mut writer = writer.lock().expect("audit log lock poisoned");
+
+        let mut line = match serde_json::to_value(&record) {
+            Ok(Value::Object(line)) => line,
+            _ => {
+                error!("Unable to serialise audit record: {record:?}");
+                return
+            },
+        };
+        line.insert("ts".to_string(), get_timestamp().into());
+
+        let mut hash = None;
+        if let Some(prev_hash) = &writer.last_hash {
+            line.insert("prev_hash".to_string(), prev_hash.clone().into());
+            let line_hash = sha256::digest(Value::Object(line.clone()).to_string());
+            line.insert("hash".to_string(), line_hash.clone().into());
+            hash = Some(line_hash);
+        }
+
+        // Only move the chain on once the line is written, so a failed write
+        // doesn't leave the next record pointing at a line that isn't there
+        let line = format!("{}\n", Value::Object(line));
+        if let Err(e) = writer.file.write_all(line.as_bytes()).and_then(|_| writer.file.flush()) {
+            error!("Writing audit record failed: {e:?} {record:?}");
+            return
+        }
+
+        if hash.is_some() {
+            writer.last_hash = hash;
+        }
+    }
+}
+
+// Hash of the last record in an existing log, to continue the chain
+fn last_hash(path: &Path) -> Result<Option<String>> {
+    let file = match File::open(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(anyhow!("Unable to read audit log {path:?}: {e}")),
+        Ok(file) => file,
+    };
+
+    let mut last_line = None;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            last_line = Some(line);
+        }
+    }
+
+    let last_line = match last_line {
+        None => return Ok(None),
+        Some(last_line) => last_line,
+    };
+
+    let record: Value = serde_json::from_str(&last_line)?;
+    match record.get("hash").and_then(Value::as_str) {
+        Some(hash) => Ok(Some(hash.to_string())),
+        None => Err(anyhow!("Audit log {path:?} isn't hash chained. Use a new file to enable hash_chain")),
+    }
+}
+
+/// Check every record's hash and link to the previous record. Returns the number of records
+pub fn verify_chain(path: &Path) -> Result<usize> {
+    let file = File::open(path).map_err(|e| anyhow!("Unable to read audit log {path:?}: {e}"))?;
+
+    let mut prev_hash = GENESIS_HASH.to_string();
+    let mut records = 0;
+
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line_number = index + 1;
+
+        if line.trim().is_empty() {
+            continue
+        }
+
+        let mut record = match serde_json::from_str::<Value>(&line) {
+            Ok(Value::Object(record)) => record,
+            _ => return Err(anyhow!("line {line_number}: not a JSON object")),
+        };
+
+        let hash = match record.remove("hash") {
+            Some(Value::String(hash)) => hash,
+            _ => return Err(anyhow!("line {line_number}: missing hash")),
+        };
+
+        if record.get("prev_hash").and_then(Value::as_str) != Some(prev_hash.as_str()) {
+            return Err(anyhow!("line {line_number}: prev_hash doesn't match the previous record"))
+        }
+
+        if sha256::digest(Value::Object(record).to_string()) != hash {
+            return Err(anyhow!("line {line_number}: hash doesn't match the record"))
+        }
+
+        prev_hash = hash;
+        records += 1;
+    }
+
+    Ok(records)
+}
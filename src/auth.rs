@@ -0,0 +1,121 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use crate::clock::Clock;
+use crate::config::AppConfig;
+use crate::get_event_first_tag_with_value;
+use nostr_rs_relay::event::Event;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub const HTTP_AUTH_KIND: u64 = 27235;
+
+/// The HTTP request a NIP-98 event must be bound to
+#[derive(Debug)]
+pub struct HttpAuthRequest<'a> {
+    /// Absolute URLs the request may have been made to (the `u` tag must match one)
+    pub urls: &'a [String],
+    pub method: &'a str,
+    /// Request body, checked against the `payload` tag when the event has one
+    pub body: Option<&'a [u8]>,
+    /// Require a `payload` tag, so the event can't be replayed with a different body
+    pub payload_required: bool,
+    /// Events already accepted, so each can only be used once (None doesn't check)
+    pub seen_events: Option<&'a SeenAuthEvents>,
+}
+
+/// Accepted NIP-98 event ids, kept until their created_at window has passed
+/// (after which the window check rejects them anyway)
+#[derive(Default, Debug)]
+pub struct SeenAuthEvents {
+    // Event id to when it can be forgotten
+    events: Mutex<HashMap<String, u64>>,
+}
+
+impl SeenAuthEvents {
+
+    /// Record an accepted event. Fails if it has already been used
+    pub fn insert(&self, event_id: &str, expires_at: u64, now: u64) -> Result<()> {
+        let mut events = self.events.lock().expect("seen auth events lock poisoned");
+        events.retain(|_, expires_at| *expires_at >= now);
+
+        if events.insert(event_id.to_string(), expires_at).is_some() {
+            return Err(anyhow!("Authorization event already used"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Ensure created_at is within delta_sec of now (either side)
+pub fn check_created_at_window(clock: &dyn Clock, created_at: u64, delta_sec: u64) -> Result<()> {
+    let now = clock.now();
+
+    if !(now.saturating_sub(delta_sec)..=now.saturating_add(delta_sec)).contains(&created_at) {
+        return Err(anyhow!("Invalid event created_at. Must be within {delta_sec} seconds of now"));
+    }
+
+    Ok(())
+}
+
+/// Verify a NIP-98 `Authorization: Nostr <base64-event>` header value, returning the authenticated pubkey
+pub fn verify_http_auth(app_config: &AppConfig, authorization: &str, request: &HttpAuthRequest) -> Result<String> {
+
+    let encoded_event = authorization.strip_prefix("Nostr ")
+        .ok_or_else(|| anyhow!("Authorization scheme must be Nostr"))?;
+
+    let event_json = base64::engine::general_purpose::STANDARD.decode(encoded_event.trim())
+        .map_err(|_| anyhow!("Authorization event isn't valid base64"))?;
+
+    let event: Event = serde_json::from_slice(&event_json)
+        .map_err(|_| anyhow!("Authorization event isn't a valid event"))?;
+
+    verify_http_auth_event(app_config, &event, request)?;
+
+    Ok(event.pubkey)
+}
+
+pub fn verify_http_auth_event(app_config: &AppConfig, event: &Event, request: &HttpAuthRequest) -> Result<()> {
+
+    // Ensure event is valid (id and signature)
+    event.validate()?;
+
+    // Ensure event kind is 27235
+    if event.kind != HTTP_AUTH_KIND {
+        return Err(anyhow!("Invalid event kind"));
+    }
+
+    // Ensure event created_at is reasonable (within 60 seconds by default)
+    check_created_at_window(app_config.clock.as_ref(), event.created_at, app_config.http_auth_created_at_delta_sec)?;
+
+    // Ensure u tag matches the request url
+    let url = get_event_first_tag_with_value(event, "u").ok_or_else(|| anyhow!("Missing u tag"))?;
+    if !request.urls.contains(&url) {
+        return Err(anyhow!("Invalid u tag"));
+    }
+
+    // Ensure method tag matches the request method
+    let method = get_event_first_tag_with_value(event, "method").ok_or_else(|| anyhow!("Missing method tag"))?;
+    if !method.eq_ignore_ascii_case(request.method) {
+        return Err(anyhow!("Invalid method tag"));
+    }
+
+    // Ensure payload tag (required with a body) matches the SHA-256 of the request body
+    match get_event_first_tag_with_value(event, "payload") {
+        None if request.payload_required => return Err(anyhow!("Missing payload tag")),
+        None => {},
+        Some(payload) => {
+            let body_hash = sha256::digest(request.body.unwrap_or_default());
+            if !payload.eq_ignore_ascii_case(&body_hash) {
+                return Err(anyhow!("Invalid payload tag"));
+            }
+        },
+    }
+
+    // Ensure the event hasn't already been used
+    if let Some(seen_events) = request.seen_events {
+        let expires_at = event.created_at.saturating_add(app_config.http_auth_created_at_delta_sec);
+        seen_events.insert(&event.id, expires_at, app_config.clock.now())?;
+    }
+
+    Ok(())
+}
@@ -1,12 +1,20 @@
 #[macro_use]
 extern crate log;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::Parser;
 use dotenv::dotenv;
-use nostrgraph_pow_service::config::{AppArgs, AppConfig};
+use nostrgraph_pow_service::admin::AdminStore;
+use nostrgraph_pow_service::audit::{self, AuditLog};
+use nostrgraph_pow_service::config::{AppArgs, Command, ConfigStore, Settings};
+use nostrgraph_pow_service::delegation::GrantStore;
+use nostrgraph_pow_service::{health, http};
+use nostrgraph_pow_service::metrics::metrics;
+use nostrgraph_pow_service::registry::{self, MinedRegistry};
+use nostrgraph_pow_service::state::AppState;
 use nostrgraph_pow_service::websocket::ws_connect;
 use serde_json::json;
 use std::sync::Arc;
+use tokio::time::Duration;
 use warp::Filter;
 use warp_real_ip::real_ip;
 
@@ -20,51 +28,135 @@ async fn main() -> Result<()> {
 
     let args = AppArgs::parse();
 
-    let app_config = Arc::new(AppConfig::new(
-        args.relay_identifier,
-        args.pubkey_whitelist,
-        args.min_pow_difficulty,
-        args.max_pow_difficulty
-    ));
+    if let Some(Command::VerifyAuditLog { path }) = &args.command {
+        let path = match path {
+            Some(path) => path.clone(),
+            None => Settings::load(&args)?.audit.log_file
+                .ok_or_else(|| anyhow!("No audit log configured. Pass the audit log path"))?,
+        };
 
-    let app_config_warp = warp::any().map(move || Arc::clone(&app_config));
+        let records = audit::verify_chain(&path)?;
+        println!("Audit log {path:?} is intact: {records} records");
 
-    // This allows us get an real source IP Address if behind Nginx
+        return Ok(())
+    }
+
+    let config_store = Arc::new(ConfigStore::load(args).await?);
+    let socket_addr = config_store.current().await.socket_addr;
+    let max_message_size = config_store.current().await.websocket.max_message_size;
+
+    // Reload config on SIGHUP or config file changes
+    let config_watcher = Arc::clone(&config_store);
+    tokio::spawn(async move {
+        if let Err(e) = config_watcher.watch().await {
+            error!("Config watcher stopped: {e:?}");
+        }
+    });
+
+    let audit_log = AuditLog::open(&config_store.current().await.audit)?;
+    let mined_registry = MinedRegistry::open(&config_store.current().await.registry)?;
+    let grants = GrantStore::open(config_store.current().await.delegation.grants_file.as_deref())?;
+
+    let admin_store = AdminStore::new(config_store.current().await.admin_state_file.as_deref());
+
+    let state = Arc::new(AppState::new(config_store, audit_log, mined_registry, grants, admin_store));
+    state.admin.restore(&state).await?;
+
+    // /readyz reports not ready until the miner self-test passes. Then measure
+    // the hashrate, to work out the highest difficulty we can mine within the SLA
+    let startup_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        startup_state.health.run_self_test().await;
+
+        let benchmark_sec = startup_state.config.current().await.calibration.benchmark_sec;
+        startup_state.calibration.benchmark(Duration::from_secs(benchmark_sec)).await;
+    });
+
+    // Drop expired mined registry records
+    let registry_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        let mut prune_interval = tokio::time::interval(registry::PRUNE_INTERVAL);
+        loop {
+            prune_interval.tick().await;
+            registry_state.registry.prune();
+        }
+    });
+
+    // Health and readiness probes
+    let health_routes = health::routes(Arc::clone(&state));
+
+    // HTTP API routes (NIP-98 authenticated)
     let proxy_addr = [127, 0, 0, 1].into();
+    let http_routes = http::routes(Arc::clone(&state), vec![proxy_addr], max_message_size as u64);
+
+    let state_warp = warp::any().map(move || Arc::clone(&state));
+
+    // This allows us get an real source IP Address if behind Nginx
     let real_ip_warp = warp::any()
         .and(real_ip(vec![proxy_addr]));
 
     // https://github.com/nostr-protocol/nips/blob/master/11.md
-    let server_info = json!({
-        "name": "Nostr PoW Service Provider",
-        "description": "Nostr Proof of Work Service Provider",
-        // "pubkey": "",
-        // "contact": "",
-        // "supported_nips": [], // TODO: add NIP-XX once we have a number
-        "software": "Nostr PoW Service",
-        "version": "Infinite"
-    });
     let server_info_route = warp::path::end()
       .and(warp::header::exact("ACCEPT", "application/nostr+json"))
-      .map(move || {
+      .and(state_warp.clone())
+      .and_then(|state: Arc<AppState>| async move {
           debug!("Request for server info");
-          warp::reply::json(&server_info)
+
+          let app_config = state.config.current().await;
+          let calibration = state.calibration.summary(&app_config.calibration);
+
+          // Offered difficulty, lowered to the calibrated difficulty when clamping
+          let max_pow_difficulty = match (app_config.calibration.clamp_max_pow_difficulty, calibration.max_pow_difficulty) {
+              (true, Some(calibrated)) => app_config.max_pow_difficulty.min(calibrated),
+              _ => app_config.max_pow_difficulty,
+          };
+
+          let server_info = json!({
+              "name": "Nostr PoW Service Provider",
+              "description": "Nostr Proof of Work Service Provider",
+              // "pubkey": "",
+              // "contact": "",
+              // "supported_nips": [], // TODO: add NIP-XX once we have a number
+              "software": "Nostr PoW Service",
+              "version": "Infinite",
+              "pow": {
+                  "min_pow_difficulty": app_config.min_pow_difficulty,
+                  "max_pow_difficulty": max_pow_difficulty,
+                  "calibration": calibration,
+              }
+          });
+
+          Ok::<_, warp::Rejection>(warp::reply::json(&server_info))
       });
 
+    // Prometheus metrics
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .and(state_warp.clone())
+        .map(|state: Arc<AppState>| {
+            warp::reply::with_header(metrics().render(&state), "content-type", "text/plain; version=0.0.4")
+        });
+
     let websocket_route = warp::path::end()
         .and(warp::ws())
-        .and(app_config_warp)
+        .and(state_warp)
         .and(real_ip_warp)
-        .map(|ws: warp::ws::Ws, app_config, real_ip|
-            ws.on_upgrade(move |socket|
-                ws_connect(socket, app_config, real_ip)
+        .and_then(|ws: warp::ws::Ws, state: Arc<AppState>, real_ip| async move {
+            let max_message_size = state.config.current().await.websocket.max_message_size;
+
+            Ok::<_, warp::Rejection>(
+                ws.max_message_size(max_message_size)
+                  .max_frame_size(max_message_size)
+                  .on_upgrade(move |socket|
+                    ws_connect(socket, state, real_ip)
+                )
             )
-        );
+        });
 
-    let routes = server_info_route.or(websocket_route);
+    let routes = server_info_route.or(websocket_route).or(metrics_route).or(health_routes).or(http_routes);
 
-    println!("Starting server: {}", args.socket_addr);
-    warp::serve(routes).run(args.socket_addr).await;
+    println!("Starting server: {socket_addr}");
+    warp::serve(routes).run(socket_addr).await;
 
     Ok(())
 }
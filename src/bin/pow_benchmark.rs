@@ -1,6 +1,8 @@
 use anyhow::Result;
-use nostrgraph_pow_service::pow::generate_pow;
+use nostrgraph_pow_service::clock::ManualClock;
+use nostrgraph_pow_service::pow::{generate_pow, MiningControl, MiningOptions, NonceStrategy};
 use nostr_rs_relay::event::Event;
+use std::sync::Arc;
 use tokio::time::Instant;
 
 #[tokio::main]
@@ -18,13 +20,21 @@ async fn main() -> Result<()> {
         tagidx: None,
     };
 
+    // Seeded nonces and a fixed clock, so every run does the same work
+    let clock = Arc::new(ManualClock::default());
+
     for difficulty in 10..=25 {
 
         let start = Instant::now();
         let iterations = 10;
 
-        for _ in 1..=iterations {
-          generate_pow(difficulty, event.clone()).await?;
+        for seed in 1..=iterations {
+          let options = MiningOptions {
+              nonce_strategy: NonceStrategy::Seeded { seed },
+              clock: clock.clone(),
+              ..Default::default()
+          };
+          generate_pow(difficulty, event.clone(), Arc::new(MiningControl::default()), options).await?;
         }
         let duration = Instant::now().duration_since(start).as_millis();
 
@@ -0,0 +1,137 @@
+use crate::config::CalibrationSettings;
+use crate::get_timestamp;
+use crate::pow::{generate_pow_event, MiningControl, MiningOptions, MiningProgress};
+use nostr_rust::events::EventPrepare;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::task;
+use tokio::time::{sleep, Duration};
+
+// Jobs shorter than this are too noisy to learn the hashrate from
+const MIN_SAMPLE_MS: u128 = 1000;
+// Weight of each new sample in the moving average
+const SAMPLE_WEIGHT: f64 = 0.2;
+// Jobs without a configured time budget get until their 99th percentile mining time
+const TIME_BUDGET_QUANTILE: f64 = 0.99;
+const MIN_TIME_BUDGET: Duration = Duration::from_secs(1);
+const BENCHMARK_PUBKEY: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+/// Tracks the hashrate of a single miner, from a startup benchmark and then
+/// finished jobs, to work out the highest difficulty we can mine within the SLA
+#[derive(Default)]
+pub struct Calibration {
+    // Hashes per second, None until benchmarked
+    hashrate: Mutex<Option<f64>>,
+}
+
+/// How long a job is expected to take at the current hashrate
+#[derive(Serialize, Debug, Clone)]
+pub struct JobEta {
+    pub expected_sec: f64,
+    pub p95_sec: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CalibrationSummary {
+    pub hashrate: Option<f64>,
+    pub sla_sec: f64,
+    pub sla_confidence: f64,
+    pub max_pow_difficulty: Option<u16>,
+}
+
+impl Calibration {
+
+    /// Mine a throwaway event (at an unreachable difficulty) for `duration` to measure the hashrate
+    pub async fn benchmark(&self, duration: Duration) {
+        let control = Arc::new(MiningControl::default());
+
+        let event = EventPrepare {
+            pub_key: BENCHMARK_PUBKEY.to_string(),
+            created_at: get_timestamp(),
+            kind: 1,
+            tags: vec![],
+            content: "nostr pow service benchmark".to_string(),
+        };
+
+        let miner_control = Arc::clone(&control);
+        let miner = task::spawn_blocking(move || generate_pow_event(event, 256, &miner_control, &MiningOptions::default()));
+
+        sleep(duration).await;
+        control.cancel();
+        let _ = miner.await;
+
+        let progress = control.progress(0);
+        info!("Benchmark: {} hashes in {} ms ({:.0} H/s)", progress.attempts, progress.elapsed_ms, progress.hashrate);
+
+        *self.hashrate.lock().expect("calibration lock poisoned") = Some(progress.hashrate);
+    }
+
+    /// Learn from a finished (or cancelled) job
+    pub fn record(&self, progress: &MiningProgress) {
+        if progress.elapsed_ms < MIN_SAMPLE_MS || progress.hashrate <= 0.0 {
+            return
+        }
+
+        let mut hashrate = self.hashrate.lock().expect("calibration lock poisoned");
+        *hashrate = Some(match *hashrate {
+            None => progress.hashrate,
+            Some(current) => current + SAMPLE_WEIGHT * (progress.hashrate - current),
+        });
+    }
+
+    pub fn hashrate(&self) -> Option<f64> {
+        *self.hashrate.lock().expect("calibration lock poisoned")
+    }
+
+    /// Highest difficulty expected to finish within the SLA, None until benchmarked
+    pub fn max_difficulty(&self, settings: &CalibrationSettings) -> Option<u16> {
+        self.hashrate().map(|hashrate| max_difficulty_within(hashrate, settings.sla_sec, settings.sla_confidence))
+    }
+
+    /// Expected and 95th percentile mining time for a difficulty, None until benchmarked
+    pub fn eta(&self, difficulty: u16) -> Option<JobEta> {
+        self.hashrate().map(|hashrate| JobEta {
+            expected_sec: 2f64.powi(difficulty as i32) / hashrate,
+            p95_sec: percentile_sec(hashrate, difficulty, 0.95),
+        })
+    }
+
+    /// Mining time allowed for a job. A configured budget of 0 derives it from the
+    /// difficulty and hashrate (None, so unlimited, until benchmarked)
+    pub fn time_budget(&self, difficulty: u16, budget_sec: u64) -> Option<Duration> {
+        match budget_sec {
+            0 => self.hashrate()
+                .map(|hashrate| Duration::from_secs_f64(percentile_sec(hashrate, difficulty, TIME_BUDGET_QUANTILE).min(u32::MAX as f64)))
+                .map(|budget| budget.max(MIN_TIME_BUDGET)),
+            budget_sec => Some(Duration::from_secs(budget_sec)),
+        }
+    }
+
+    pub fn summary(&self, settings: &CalibrationSettings) -> CalibrationSummary {
+        CalibrationSummary {
+            hashrate: self.hashrate(),
+            sla_sec: settings.sla_sec,
+            sla_confidence: settings.sla_confidence,
+            max_pow_difficulty: self.max_difficulty(settings),
+        }
+    }
+}
+
+/// Each attempt finds difficulty `d` with probability 2^-d, so n attempts succeed
+/// with probability 1 - (1 - 2^-d)^n ≈ 1 - e^(-n / 2^d). For a given confidence
+/// that needs n >= -ln(1 - confidence) * 2^d, and we can make hashrate * sla_sec attempts
+pub fn max_difficulty_within(hashrate: f64, sla_sec: f64, confidence: f64) -> u16 {
+    let attempts = hashrate * sla_sec;
+    let confidence_factor = -(1.0 - confidence).ln();
+
+    match attempts / confidence_factor {
+        ratio if ratio >= 1.0 => ratio.log2().floor().min(256.0) as u16,
+        _ => 0,
+    }
+}
+
+/// Seconds within which a job at `difficulty` finishes with probability `quantile`.
+/// The inverse of the success probability above: -ln(1 - quantile) * 2^d / hashrate
+pub fn percentile_sec(hashrate: f64, difficulty: u16, quantile: f64) -> f64 {
+    -(1.0 - quantile).ln() * 2f64.powi(difficulty as i32) / hashrate
+}
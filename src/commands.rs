@@ -1,13 +1,19 @@
 use anyhow::{anyhow,Result};
+use crate::admin::AdminRequest;
+use crate::signer::BunkerUri;
 use nostr_rs_relay::event::Event;
 use serde::{Deserialize, Serialize};
 
 /// Supported Nostr Commands
-#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
+#[derive(Deserialize, Clone, PartialEq, Debug)]
 #[serde(untagged)]
 pub enum NostrMessage {
+    // Before AuthMsg, which has the same shape
+    VerifyMsg(VerifyCmd),
+    PublishMsg(PublishCmd),
     AuthMsg(AuthCmd),
     PowMsg(PowCmd),
+    AdminMsg(AdminCmd),
 }
 
 /// ["AUTH", {AUTH_EVENT}]
@@ -32,26 +38,85 @@ impl From<AuthCmd> for Result<Auth> {
     }
 }
 
-/// ["POW", TARGET_POW, {POW_EVENT}, PUBLISH]
+/// ["POW", TARGET_POW, {POW_EVENT}, {OPTIONS}]
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct PowCmd {
     pub cmd: String,
     pub target_pow: u16,
     pub event: Event,
+    #[serde(default)]
+    pub options: PowOptions,
+}
+
+/// Optional POW request settings
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PowOptions {
+    /// Lowest difficulty accepted if the job runs out of time (otherwise it fails and is refunded)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_pow: Option<u16>,
+    /// Best effort: mine for at most this many seconds, returning the best result (at least min_pow)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline_sec: Option<u64>,
+    /// Keep the event's created_at (by default it's bumped to when the nonce was found)
+    #[serde(default)]
+    pub preserve_created_at: bool,
+    /// Have this NIP-46 bunker sign the mined event, and return it signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bunker: Option<BunkerUri>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct Pow {
     pub target_pow: u16,
     pub event: Event,
+    pub options: PowOptions,
 }
 
 impl From<PowCmd> for Result<Pow> {
     fn from(msg: PowCmd) -> Result<Pow> {
         if msg.cmd == "POW" {
-            Ok(Pow { target_pow: msg.target_pow, event: msg.event })
+            Ok(Pow { target_pow: msg.target_pow, event: msg.event, options: msg.options })
         } else {
             Err(anyhow!("Unknown command"))
         }
     }
 }
+
+/// ["VERIFY", {MINED_EVENT}]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct VerifyCmd {
+    pub cmd: VerifyTag,
+    pub event: Event,
+}
+
+/// Only matches "VERIFY", so other commands with an event aren't parsed as VERIFY
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub enum VerifyTag {
+    #[serde(rename = "VERIFY")]
+    Verify,
+}
+
+/// ["PUBLISH", {SIGNED_MINED_EVENT}, [RELAY_URLS]]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct PublishCmd {
+    pub cmd: PublishTag,
+    pub event: Event,
+    /// Relays to publish to (the configured default relays when empty)
+    #[serde(default)]
+    pub relays: Vec<String>,
+}
+
+/// Only matches "PUBLISH", like VerifyTag
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub enum PublishTag {
+    #[serde(rename = "PUBLISH")]
+    Publish,
+}
+
+/// ["ADMIN", {"method": METHOD, "params": {PARAMS}}]
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct AdminCmd {
+    pub cmd: String,
+    pub request: AdminRequest,
+}
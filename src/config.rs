@@ -1,45 +1,846 @@
-use clap::Parser;
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use crate::admission::AdmissionClient;
+use crate::clock::{Clock, SystemClock};
+use crate::policy::EventPolicy;
+use crate::pow::{MiningOptions, NonceTagPosition};
+use crate::profile::{Profile, ProfileSettings, DEFAULT_PROFILE, WHITELIST_PROFILE};
+use crate::relay::RelayAccess;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::{interval, Duration};
 
-#[derive(Parser, Debug)]
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct AppArgs {
+   /// TOML config file. Values set in the file override command line and env values
+   #[arg(long, env="CONFIG_FILE")]
+   pub config_file: Option<PathBuf>,
+
    #[arg(long, env="LISTEN", default_value="127.0.0.1:3030")]
    pub socket_addr: SocketAddr,
 
    #[arg(long, env="RELAY_IDENTIFIER", default_value="ws://127.0.0.1")]
    pub relay_identifier: String,
 
+   /// Public base URL of the HTTP API (e.g. https://pow.example.com), checked against NIP-98 u tags
+   #[arg(long, env="PUBLIC_URL")]
+   pub public_url: Option<String>,
+
    #[arg(long, env="PUBKEY_WHITELIST", default_value="", value_delimiter=',')]
    pub pubkey_whitelist: Vec<String>,
 
+   /// Pubkeys allowed to use the admin API (NIP-42 or NIP-98 authenticated)
+   #[arg(long, env="ADMIN_PUBKEYS", default_value="", value_delimiter=',')]
+   pub admin_pubkeys: Vec<String>,
+
+   /// JSON file to keep admin API bans, profile assignments and config changes in across restarts (in memory only if unset)
+   #[arg(long, env="ADMIN_STATE_FILE")]
+   pub admin_state_file: Option<PathBuf>,
+
+   /// Relays PUBLISH sends to when the client doesn't list any
+   #[arg(long, env="DEFAULT_RELAYS", default_value="", value_delimiter=',')]
+   pub default_relays: Vec<String>,
+
+   /// Only connect to these relay hosts for PUBLISH and bunker relays (which may then be private or ws://).
+   /// Unset allows any wss:// relay on a public address
+   #[arg(long, env="RELAY_ALLOWLIST", default_value="", value_delimiter=',')]
+   pub relay_allowlist: Vec<String>,
+
    #[arg(long, env="MIN_POW_DIFFICULTY", default_value="10")]
    pub min_pow_difficulty: u16,
 
    #[arg(long, env="MAX_POW_DIFFICULTY", default_value="25")]
    pub max_pow_difficulty: u16,
+
+   /// Profile for pubkeys not assigned to any other profile (profiles are set in the config file)
+   #[arg(long, env="DEFAULT_PROFILE", default_value=DEFAULT_PROFILE)]
+   pub default_profile: String,
+
+   /// Events mined at once, with other jobs queued by profile priority (0 uses all CPUs)
+   #[arg(long, env="MINING_THREADS", default_value="0")]
+   pub mining_threads: usize,
+
+   /// Seconds a job may mine before it's stopped (0 derives it from the difficulty and hashrate)
+   #[arg(long, env="JOB_TIME_BUDGET_SEC", default_value="0")]
+   pub job_time_budget_sec: u64,
+
+   /// Where the miner puts the nonce tag (first or last). Existing nonce tags are replaced
+   #[arg(long, env="NONCE_TAG_POSITION", value_enum, default_value="last")]
+   pub nonce_tag_position: NonceTagPosition,
+
+   /// Queued jobs above which /readyz reports not ready
+   #[arg(long, env="MAX_QUEUE_DEPTH", default_value="100")]
+   pub max_queue_depth: usize,
+
+   /// Seconds either side of now a POW event's created_at may be
+   #[arg(long, env="CREATED_AT_DELTA_SEC", default_value="600")]
+   pub created_at_delta_sec: u64,
+
+   /// Seconds either side of now a NIP-42 AUTH event's created_at may be
+   #[arg(long, env="AUTH_CREATED_AT_DELTA_SEC", default_value="300")]
+   pub auth_created_at_delta_sec: u64,
+
+   /// Seconds either side of now a NIP-98 HTTP auth event's created_at may be
+   #[arg(long, env="HTTP_AUTH_CREATED_AT_DELTA_SEC", default_value="60")]
+   pub http_auth_created_at_delta_sec: u64,
+
+   /// Seconds to wait for a NIP-46 remote signer (per bunker relay)
+   #[arg(long, env="REMOTE_SIGNER_TIMEOUT_SEC", default_value="30")]
+   pub remote_signer_timeout_sec: u64,
+
+   /// Seconds to wait for each relay's OK when publishing
+   #[arg(long, env="PUBLISH_TIMEOUT_SEC", default_value="10")]
+   pub publish_timeout_sec: u64,
+
+   /// Most relays a single PUBLISH may send to
+   #[arg(long, env="MAX_PUBLISH_RELAYS", default_value="10")]
+   pub max_publish_relays: usize,
+
+   /// Seconds between websocket pings (0 disables pings)
+   #[arg(long, env="WS_PING_INTERVAL_SEC", default_value="30")]
+   pub ws_ping_interval_sec: u64,
+
+   /// Seconds to wait for any frame after a ping before dropping the peer
+   #[arg(long, env="WS_PONG_TIMEOUT_SEC", default_value="10")]
+   pub ws_pong_timeout_sec: u64,
+
+   /// Seconds a connection may stay open without completing AUTH
+   #[arg(long, env="WS_AUTH_TIMEOUT_SEC", default_value="30")]
+   pub ws_auth_timeout_sec: u64,
+
+   /// Seconds an authenticated connection may stay idle between requests
+   #[arg(long, env="WS_IDLE_TIMEOUT_SEC", default_value="300")]
+   pub ws_idle_timeout_sec: u64,
+
+   /// Maximum inbound websocket message (and frame) size in bytes
+   #[arg(long, env="WS_MAX_MESSAGE_SIZE", default_value="65536")]
+   pub ws_max_message_size: usize,
+
+   /// Most pubkeys a single websocket connection may AUTH as
+   #[arg(long, env="WS_MAX_PUBKEYS", default_value="10")]
+   pub ws_max_pubkeys: usize,
+
+   /// Price (sats) = (difficulty - offset) ^ (difficulty / divisor)
+   #[arg(long, env="PRICE_DIFFICULTY_OFFSET", default_value="7")]
+   pub price_difficulty_offset: f32,
+
+   #[arg(long, env="PRICE_EXPONENT_DIVISOR", default_value="10")]
+   pub price_exponent_divisor: f32,
+
+   /// Event kinds we will mine (empty allows all kinds)
+   #[arg(long, env="ALLOWED_KINDS", value_delimiter=',')]
+   pub allowed_kinds: Vec<u64>,
+
+   /// Event kinds we will never mine
+   #[arg(long, env="DENIED_KINDS", value_delimiter=',')]
+   pub denied_kinds: Vec<u64>,
+
+   /// Maximum event content length in bytes
+   #[arg(long, env="MAX_CONTENT_LENGTH")]
+   pub max_content_length: Option<usize>,
+
+   /// Maximum number of event tags
+   #[arg(long, env="MAX_TAGS")]
+   pub max_tags: Option<usize>,
+
+   /// Tag names every event must carry (e.g. "t,client")
+   #[arg(long, env="REQUIRED_TAGS", value_delimiter=',')]
+   pub required_tags: Vec<String>,
+
+   /// Reject events whose content matches this regex (repeatable)
+   #[arg(long, env="CONTENT_DENY_REGEX")]
+   pub content_deny_regex: Vec<String>,
+
+   /// External write policy program (strfry plugin protocol over stdin/stdout JSON)
+   #[arg(long, env="POLICY_PLUGIN")]
+   pub policy_plugin: Option<String>,
+
+   /// gRPC event admission server (nostr-rs-relay nauthz protocol), e.g. http://[::1]:50051
+   #[arg(long, env="GRPC_ADMISSION_SERVER")]
+   pub grpc_admission_server: Option<String>,
+
+   /// Target seconds to finish a job, used to calibrate the highest difficulty offered
+   #[arg(long, env="SLA_SEC", default_value="60")]
+   pub sla_sec: f64,
+
+   /// Probability a job at the calibrated difficulty finishes within SLA_SEC
+   #[arg(long, env="SLA_CONFIDENCE", default_value="0.95")]
+   pub sla_confidence: f64,
+
+   /// Lower max_pow_difficulty to the calibrated difficulty (otherwise it's only advertised)
+   #[arg(long, env="CLAMP_MAX_POW_DIFFICULTY")]
+   pub clamp_max_pow_difficulty: bool,
+
+   /// Seconds to benchmark the miner at startup
+   #[arg(long, env="BENCHMARK_SEC", default_value="3")]
+   pub benchmark_sec: u64,
+
+   /// Append-only JSON lines audit log of payments, jobs and auth (disabled if unset)
+   #[arg(long, env="AUDIT_LOG")]
+   pub audit_log: Option<PathBuf>,
+
+   /// Hash chain audit log records, so tampering can be detected
+   #[arg(long, env="AUDIT_HASH_CHAIN")]
+   pub audit_hash_chain: bool,
+
+   /// JSON lines file to keep the mined event registry in across restarts (in memory only if unset)
+   #[arg(long, env="MINED_REGISTRY_FILE")]
+   pub mined_registry_file: Option<PathBuf>,
+
+   /// Seconds mined events are kept in the registry (0 keeps them forever)
+   #[arg(long, env="MINED_REGISTRY_RETENTION_SEC", default_value="2592000")]
+   pub mined_registry_retention_sec: u64,
+
+   /// Mine events for other pubkeys with a NIP-26 delegation from (or a grant by) the authenticated pubkey
+   #[arg(long, env="ALLOW_DELEGATED_REQUESTS")]
+   pub allow_delegated_requests: bool,
+
+   /// JSON file to keep pubkey grants in across restarts (in memory only if unset)
+   #[arg(long, env="GRANTS_FILE")]
+   pub grants_file: Option<PathBuf>,
+
+   #[command(subcommand)]
+   pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Verify the audit log hash chain, then exit
+    VerifyAuditLog {
+        /// Audit log to verify (defaults to the configured audit log)
+        path: Option<PathBuf>,
+    },
+}
+
+/// Everything that can be configured. Built from command line and env values,
+/// then overlaid with the config file (if any). Keys match the command line options.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub socket_addr: SocketAddr,
+    pub relay_identifier: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_url: Option<String>,
+    pub pubkey_whitelist: Vec<String>,
+    pub admin_pubkeys: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_state_file: Option<PathBuf>,
+    pub default_relays: Vec<String>,
+    pub relay_allowlist: Vec<String>,
+    pub min_pow_difficulty: u16,
+    pub max_pow_difficulty: u16,
+    pub default_profile: String,
+    pub limits: LimitSettings,
+    pub pricing: Pricing,
+    pub policy: PolicySettings,
+    pub admission: AdmissionSettings,
+    pub mining: MiningSettings,
+    pub calibration: CalibrationSettings,
+    pub audit: AuditSettings,
+    pub registry: RegistrySettings,
+    pub delegation: DelegationSettings,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, ProfileSettings>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LimitSettings {
+    pub mining_threads: usize,
+    pub job_time_budget_sec: u64,
+    pub max_queue_depth: usize,
+    pub created_at_delta_sec: u64,
+    pub auth_created_at_delta_sec: u64,
+    pub http_auth_created_at_delta_sec: u64,
+    pub remote_signer_timeout_sec: u64,
+    pub publish_timeout_sec: u64,
+    pub max_publish_relays: usize,
+    pub ws_ping_interval_sec: u64,
+    pub ws_pong_timeout_sec: u64,
+    pub ws_auth_timeout_sec: u64,
+    pub ws_idle_timeout_sec: u64,
+    pub ws_max_message_size: usize,
+    pub ws_max_pubkeys: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Pricing {
+    pub difficulty_offset: f32,
+    pub exponent_divisor: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PolicySettings {
+    pub allowed_kinds: Vec<u64>,
+    pub denied_kinds: Vec<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_content_length: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tags: Option<usize>,
+    pub required_tags: Vec<String>,
+    pub content_deny_regex: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plugin: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AdmissionSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grpc_server: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MiningSettings {
+    pub nonce_tag_position: NonceTagPosition,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CalibrationSettings {
+    pub sla_sec: f64,
+    pub sla_confidence: f64,
+    pub clamp_max_pow_difficulty: bool,
+    pub benchmark_sec: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AuditSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<PathBuf>,
+    pub hash_chain: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RegistrySettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    pub retention_sec: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DelegationSettings {
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grants_file: Option<PathBuf>,
+}
+
+impl From<&AppArgs> for Settings {
+    fn from(args: &AppArgs) -> Self {
+        Self {
+            socket_addr: args.socket_addr,
+            relay_identifier: args.relay_identifier.clone(),
+            public_url: args.public_url.clone(),
+            pubkey_whitelist: args.pubkey_whitelist.iter().filter(|p| !p.is_empty()).cloned().collect(),
+            admin_pubkeys: args.admin_pubkeys.iter().filter(|p| !p.is_empty()).cloned().collect(),
+            admin_state_file: args.admin_state_file.clone(),
+            default_relays: args.default_relays.iter().filter(|r| !r.is_empty()).cloned().collect(),
+            relay_allowlist: args.relay_allowlist.iter().filter(|h| !h.is_empty()).cloned().collect(),
+            min_pow_difficulty: args.min_pow_difficulty,
+            max_pow_difficulty: args.max_pow_difficulty,
+            default_profile: args.default_profile.clone(),
+            limits: LimitSettings {
+                mining_threads: args.mining_threads,
+                job_time_budget_sec: args.job_time_budget_sec,
+                max_queue_depth: args.max_queue_depth,
+                created_at_delta_sec: args.created_at_delta_sec,
+                auth_created_at_delta_sec: args.auth_created_at_delta_sec,
+                http_auth_created_at_delta_sec: args.http_auth_created_at_delta_sec,
+                remote_signer_timeout_sec: args.remote_signer_timeout_sec,
+                publish_timeout_sec: args.publish_timeout_sec,
+                max_publish_relays: args.max_publish_relays,
+                ws_ping_interval_sec: args.ws_ping_interval_sec,
+                ws_pong_timeout_sec: args.ws_pong_timeout_sec,
+                ws_auth_timeout_sec: args.ws_auth_timeout_sec,
+                ws_idle_timeout_sec: args.ws_idle_timeout_sec,
+                ws_max_message_size: args.ws_max_message_size,
+                ws_max_pubkeys: args.ws_max_pubkeys,
+            },
+            pricing: Pricing {
+                difficulty_offset: args.price_difficulty_offset,
+                exponent_divisor: args.price_exponent_divisor,
+            },
+            policy: PolicySettings {
+                allowed_kinds: args.allowed_kinds.clone(),
+                denied_kinds: args.denied_kinds.clone(),
+                max_content_length: args.max_content_length,
+                max_tags: args.max_tags,
+                required_tags: args.required_tags.clone(),
+                content_deny_regex: args.content_deny_regex.clone(),
+                plugin: args.policy_plugin.clone(),
+            },
+            admission: AdmissionSettings {
+                grpc_server: args.grpc_admission_server.clone(),
+            },
+            mining: MiningSettings {
+                nonce_tag_position: args.nonce_tag_position,
+            },
+            calibration: CalibrationSettings {
+                sla_sec: args.sla_sec,
+                sla_confidence: args.sla_confidence,
+                clamp_max_pow_difficulty: args.clamp_max_pow_difficulty,
+                benchmark_sec: args.benchmark_sec,
+            },
+            audit: AuditSettings {
+                log_file: args.audit_log.clone(),
+                hash_chain: args.audit_hash_chain,
+            },
+            registry: RegistrySettings {
+                file: args.mined_registry_file.clone(),
+                retention_sec: args.mined_registry_retention_sec,
+            },
+            delegation: DelegationSettings {
+                enabled: args.allow_delegated_requests,
+                grants_file: args.grants_file.clone(),
+            },
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+impl Settings {
+
+    /// Command line and env values, overlaid with the config file (if any)
+    pub fn load(args: &AppArgs) -> Result<Self> {
+        let settings = Settings::from(args);
+
+        let path = match &args.config_file {
+            None => return Ok(settings),
+            Some(path) => path,
+        };
+
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Unable to read config file {path:?}: {e}"))?;
+        let file_values: toml::Value = toml::from_str(&contents)
+            .map_err(|e| anyhow!("Unable to parse config file {path:?}: {e}"))?;
+
+        let mut values = toml::Value::try_from(&settings)?;
+        merge_toml(&mut values, file_values);
+
+        values.try_into()
+            .map_err(|e| anyhow!("Invalid config file {path:?}: {e}"))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+
+        if self.min_pow_difficulty > self.max_pow_difficulty {
+            return Err(anyhow!("min_pow_difficulty ({}) is greater than max_pow_difficulty ({})", self.min_pow_difficulty, self.max_pow_difficulty))
+        }
+
+        // Event ids are 256 bits
+        if self.max_pow_difficulty > 256 {
+            return Err(anyhow!("max_pow_difficulty must be 256 or less"))
+        }
+
+        if self.relay_identifier.is_empty() {
+            return Err(anyhow!("relay_identifier is required"))
+        }
+
+        if let Some(pubkey) = self.pubkey_whitelist.iter().find(|p| !is_hex_pubkey(p)) {
+            return Err(anyhow!("pubkey_whitelist has an invalid hex pubkey: {pubkey}"))
+        }
+
+        if let Some(pubkey) = self.admin_pubkeys.iter().find(|p| !is_hex_pubkey(p)) {
+            return Err(anyhow!("admin_pubkeys has an invalid hex pubkey: {pubkey}"))
+        }
+
+        if let Some(relay) = self.default_relays.iter().find(|r| !is_relay_url(r)) {
+            return Err(anyhow!("default_relays has an invalid relay url: {relay}"))
+        }
+
+        if let Some(host) = self.relay_allowlist.iter().find(|h| h.is_empty() || h.contains('/')) {
+            return Err(anyhow!("relay_allowlist has an invalid host: {host} (expected a host, e.g. relay.example.com)"))
+        }
+
+        for (name, profile) in &self.profiles {
+            if let Some(pubkey) = profile.pubkeys.iter().find(|p| !is_hex_pubkey(p)) {
+                return Err(anyhow!("profile {name} has an invalid hex pubkey: {pubkey}"))
+            }
+        }
+
+        if self.default_profile != DEFAULT_PROFILE && !self.profiles.contains_key(&self.default_profile) {
+            return Err(anyhow!("default_profile {} is not a configured profile", self.default_profile))
+        }
+
+        if self.limits.ws_pong_timeout_sec == 0 || self.limits.ws_auth_timeout_sec == 0 || self.limits.ws_idle_timeout_sec == 0 {
+            return Err(anyhow!("websocket timeouts must be greater than 0"))
+        }
+
+        if self.limits.ws_max_pubkeys == 0 {
+            return Err(anyhow!("ws_max_pubkeys must be greater than 0"))
+        }
+
+        if self.pricing.exponent_divisor <= 0.0 {
+            return Err(anyhow!("pricing exponent_divisor must be greater than 0"))
+        }
+
+        if self.calibration.sla_sec <= 0.0 {
+            return Err(anyhow!("calibration sla_sec must be greater than 0"))
+        }
+
+        if !(self.calibration.sla_confidence > 0.0 && self.calibration.sla_confidence < 1.0) {
+            return Err(anyhow!("calibration sla_confidence must be between 0 and 1"))
+        }
+
+        Ok(())
+    }
+}
+
+// Recursively overlay tables, with overlay values replacing base values
+fn merge_toml(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_toml(base_value, value),
+                    None => { base.insert(key, value); },
+                }
+            }
+        },
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn is_hex_pubkey(pubkey: &str) -> bool {
+    pubkey.len() == 64 && pubkey.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+pub fn is_relay_url(url: &str) -> bool {
+    url.starts_with("wss://") || url.starts_with("ws://")
+}
+
+/// Websocket keepalive and limits
+#[derive(Debug, Clone)]
+pub struct WebsocketConfig {
+    pub ping_interval: Option<Duration>,
+    pub pong_timeout: Duration,
+    pub auth_timeout: Duration,
+    pub idle_timeout: Duration,
+    pub max_message_size: usize,
+    pub max_pubkeys: usize,
+}
+
+impl From<&LimitSettings> for WebsocketConfig {
+    fn from(limits: &LimitSettings) -> Self {
+        Self {
+            ping_interval: match limits.ws_ping_interval_sec {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            pong_timeout: Duration::from_secs(limits.ws_pong_timeout_sec),
+            auth_timeout: Duration::from_secs(limits.ws_auth_timeout_sec),
+            idle_timeout: Duration::from_secs(limits.ws_idle_timeout_sec),
+            max_message_size: limits.ws_max_message_size,
+            max_pubkeys: limits.ws_max_pubkeys,
+        }
+    }
+}
+
+impl From<&MiningSettings> for MiningOptions {
+    fn from(mining: &MiningSettings) -> Self {
+        Self {
+            nonce_tag_position: mining.nonce_tag_position,
+            ..Default::default()
+        }
+    }
 }
 
 pub struct AppConfig {
+    pub socket_addr: SocketAddr,
     pub relay_identifier: String,
+    pub public_url: Option<String>,
     pub pubkey_whitelist: Vec<String>,
+    pub admin_pubkeys: Vec<String>,
+    pub admin_state_file: Option<PathBuf>,
+    pub default_relays: Vec<String>,
+    pub relay_access: RelayAccess,
     pub min_pow_difficulty: u16,
     pub max_pow_difficulty: u16,
+    pub websocket: WebsocketConfig,
+    pub mining_threads: usize,
+    pub job_time_budget_sec: u64,
+    pub max_queue_depth: usize,
+    pub created_at_delta_sec: u64,
+    pub auth_created_at_delta_sec: u64,
+    pub http_auth_created_at_delta_sec: u64,
+    pub remote_signer_timeout: Duration,
+    pub publish_timeout: Duration,
+    pub max_publish_relays: usize,
+    /// Time source for created_at checks and mining
+    pub clock: Arc<dyn Clock>,
+    pub pricing: Pricing,
+    pub event_policy: EventPolicy,
+    pub admission: Option<Arc<AdmissionClient>>,
+    pub mining: MiningOptions,
+    pub calibration: CalibrationSettings,
+    pub audit: AuditSettings,
+    pub registry: RegistrySettings,
+    pub delegation: DelegationSettings,
+    pub profiles: BTreeMap<String, Profile>,
+    pub default_profile: String,
 }
 
 impl AppConfig {
-  pub fn new(
-    relay_identifier: String,
-    pubkey_whitelist: Vec<String>,
-    min_pow_difficulty: u16,
-    max_pow_difficulty: u16
-  ) -> Self {
-
-    Self {
-        relay_identifier,
-        pubkey_whitelist,
-        min_pow_difficulty,
-        max_pow_difficulty,
-    }
+
+  /// Validate settings and build the runtime config. The gRPC admission
+  /// connection is kept from the previous config if the server is unchanged.
+  pub async fn build(settings: Settings, previous: Option<&AppConfig>) -> Result<Self> {
+
+    settings.validate()?;
+
+    let event_policy = EventPolicy::try_from(&settings.policy)?;
+
+    let previous_admission = previous
+        .and_then(|config| config.admission.as_ref())
+        .filter(|admission| Some(&admission.server_addr) == settings.admission.grpc_server.as_ref());
+
+    let admission = match (previous_admission, &settings.admission.grpc_server) {
+        (Some(admission), _) => Some(Arc::clone(admission)),
+        (None, Some(server_addr)) => Some(Arc::new(AdmissionClient::connect(server_addr).await)),
+        (None, None) => None,
+    };
+
+    let profiles = build_profiles(&settings)?;
+
+    // Kept across reloads, so an injected clock stays in place
+    let clock = previous
+        .map(|config| Arc::clone(&config.clock))
+        .unwrap_or_else(|| Arc::new(SystemClock));
+
+    let mining_threads = match settings.limits.mining_threads {
+        0 => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        threads => threads,
+    };
+
+    Ok(Self {
+        socket_addr: settings.socket_addr,
+        relay_identifier: settings.relay_identifier,
+        public_url: settings.public_url,
+        pubkey_whitelist: settings.pubkey_whitelist,
+        admin_pubkeys: settings.admin_pubkeys,
+        admin_state_file: settings.admin_state_file,
+        default_relays: settings.default_relays,
+        relay_access: RelayAccess { allowlist: settings.relay_allowlist },
+        min_pow_difficulty: settings.min_pow_difficulty,
+        max_pow_difficulty: settings.max_pow_difficulty,
+        websocket: WebsocketConfig::from(&settings.limits),
+        mining_threads,
+        job_time_budget_sec: settings.limits.job_time_budget_sec,
+        max_queue_depth: settings.limits.max_queue_depth,
+        created_at_delta_sec: settings.limits.created_at_delta_sec,
+        auth_created_at_delta_sec: settings.limits.auth_created_at_delta_sec,
+        http_auth_created_at_delta_sec: settings.limits.http_auth_created_at_delta_sec,
+        remote_signer_timeout: Duration::from_secs(settings.limits.remote_signer_timeout_sec),
+        publish_timeout: Duration::from_secs(settings.limits.publish_timeout_sec),
+        max_publish_relays: settings.limits.max_publish_relays,
+        mining: MiningOptions { clock: Arc::clone(&clock), ..MiningOptions::from(&settings.mining) },
+        clock,
+        pricing: settings.pricing,
+        event_policy,
+        admission,
+        calibration: settings.calibration,
+        audit: settings.audit,
+        registry: settings.registry,
+        delegation: settings.delegation,
+        profiles,
+        default_profile: settings.default_profile,
+    })
   }
+
+  /// Use another time source (e.g. a ManualClock in tests)
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.mining.clock = Arc::clone(&clock);
+    self.clock = clock;
+    self
+  }
+
+  pub fn is_admin(&self, pubkey: &str) -> bool {
+    self.admin_pubkeys.iter().any(|admin| admin == pubkey)
+  }
+
+  pub fn default_profile(&self) -> &Profile {
+    // build_profiles always includes the default profile
+    &self.profiles[&self.default_profile]
+  }
+}
+
+// Configured profiles, plus the built in default profile (global bounds, full price)
+// and whitelist profile (global bounds, free) when they aren't configured
+fn build_profiles(settings: &Settings) -> Result<BTreeMap<String, Profile>> {
+    let mut profile_settings = settings.profiles.clone();
+
+    profile_settings.entry(DEFAULT_PROFILE.to_string()).or_default();
+
+    if !settings.pubkey_whitelist.is_empty() {
+        // A configured whitelist profile keeps its own settings, but still takes the whitelisted pubkeys
+        let whitelist = profile_settings.entry(WHITELIST_PROFILE.to_string()).or_insert_with(|| ProfileSettings {
+            price_multiplier: 0.0,
+            ..Default::default()
+        });
+
+        for pubkey in &settings.pubkey_whitelist {
+            if !whitelist.pubkeys.contains(pubkey) {
+                whitelist.pubkeys.push(pubkey.clone());
+            }
+        }
+    }
+
+    let mut profiles = BTreeMap::new();
+    for (name, profile_settings) in profile_settings {
+        let profile = Profile::new(&name, &profile_settings, settings.min_pow_difficulty, settings.max_pow_difficulty);
+        profile.validate()?;
+        profiles.insert(name, profile);
+    }
+
+    Ok(profiles)
+}
+
+/// Holds the current config, and swaps in a new one when the config file is reloaded.
+/// Requests take a snapshot with `current()`, so a reload applies from their next request.
+///
+/// Admin API changes are kept as overrides on top of the config file, so they
+/// survive reloads (and restarts, with an admin state file).
+pub struct ConfigStore {
+    args: AppArgs,
+    current: RwLock<Arc<AppConfig>>,
+    overrides: Mutex<toml::Value>,
+}
+
+impl ConfigStore {
+    pub async fn load(args: AppArgs) -> Result<Self> {
+        let settings = Settings::load(&args)?;
+        let app_config = AppConfig::build(settings, None).await?;
+
+        Ok(Self {
+            args,
+            current: RwLock::new(Arc::new(app_config)),
+            overrides: Mutex::new(toml::Value::Table(toml::map::Map::new())),
+        })
+    }
+
+    /// Apply settings on top of the config file at runtime (keys as in the config file).
+    /// Only kept if the resulting config is valid
+    pub async fn apply_override(&self, overlay: toml::Value) -> Result<()> {
+        let mut overrides = self.overrides.lock().await;
+
+        let mut updated = overrides.clone();
+        merge_toml(&mut updated, overlay);
+
+        let settings = self.settings(&updated)?;
+        let previous = self.current().await;
+
+        let app_config = AppConfig::build(settings, Some(&previous)).await?;
+        *self.current.write().await = Arc::new(app_config);
+        *overrides = updated;
+
+        Ok(())
+    }
+
+    /// The admin overrides, for saving to the admin state file
+    pub async fn overrides(&self) -> toml::Value {
+        self.overrides.lock().await.clone()
+    }
+
+    // Config file settings with the admin overrides on top
+    fn settings(&self, overrides: &toml::Value) -> Result<Settings> {
+        let settings = Settings::load(&self.args)?;
+
+        let mut values = toml::Value::try_from(&settings)?;
+        merge_toml(&mut values, overrides.clone());
+
+        values.try_into().map_err(|e| anyhow!("Invalid config override: {e}"))
+    }
+
+    pub async fn current(&self) -> Arc<AppConfig> {
+        Arc::clone(&*self.current.read().await)
+    }
+
+    /// Re-read and validate the config. The current config is only replaced if it's all valid
+    pub async fn reload(&self) -> Result<()> {
+        let overrides = self.overrides.lock().await;
+
+        let settings = self.settings(&overrides)?;
+        let previous = self.current().await;
+
+        if settings.socket_addr != previous.socket_addr {
+            warn!("socket_addr changes need a restart. Still listening on {}", previous.socket_addr);
+        }
+
+        if settings.audit != previous.audit {
+            warn!("audit changes need a restart. Still using {:?}", previous.audit);
+        }
+
+        if settings.registry != previous.registry {
+            warn!("registry changes need a restart. Still using {:?}", previous.registry);
+        }
+
+        if settings.delegation.grants_file != previous.delegation.grants_file {
+            warn!("grants_file changes need a restart. Still using {:?}", previous.delegation.grants_file);
+        }
+
+        if settings.admin_state_file != previous.admin_state_file {
+            warn!("admin_state_file changes need a restart. Still using {:?}", previous.admin_state_file);
+        }
+
+        let app_config = AppConfig::build(settings, Some(&previous)).await?;
+        *self.current.write().await = Arc::new(app_config);
+
+        info!("Config reloaded");
+
+        Ok(())
+    }
+
+    /// Reload on SIGHUP, or when the config file is modified
+    pub async fn watch(self: Arc<Self>) -> Result<()> {
+
+        let path = match &self.args.config_file {
+            None => return Ok(()),
+            Some(path) => path.clone(),
+        };
+
+        let mut sighup = signal(SignalKind::hangup())?;
+        let mut poll = interval(CONFIG_POLL_INTERVAL);
+        let mut last_modified = file_modified(&path);
+
+        loop {
+            tokio::select! {
+                _ = sighup.recv() => {
+                    info!("SIGHUP received. Reloading config: {path:?}");
+                },
+
+                _ = poll.tick() => {
+                    let modified = file_modified(&path);
+                    if modified == last_modified {
+                        continue
+                    }
+                    info!("Config file changed. Reloading config: {path:?}");
+                },
+            }
+
+            last_modified = file_modified(&path);
+
+            if let Err(e) = self.reload().await {
+                error!("Config reload failed. Keeping current config: {e:?}");
+            }
+        }
+    }
+}
+
+fn file_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
 }
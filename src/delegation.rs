@@ -0,0 +1,139 @@
+use anyhow::{anyhow, Result};
+use nostr_rs_relay::event::Event;
+use secp256k1::{schnorr, Secp256k1, XOnlyPublicKey};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// The delegator of an event with a valid NIP-26 delegation tag
+/// (`["delegation", <delegator>, <conditions>, <token>]`). None if it has no delegation tag
+pub fn delegator(event: &Event) -> Result<Option<String>> {
+    let tag = match event.tags.iter().find(|tag| tag.first().map(String::as_str) == Some("delegation")) {
+        None => return Ok(None),
+        Some(tag) => tag,
+    };
+
+    let (delegator, conditions, token) = match tag.as_slice() {
+        [_, delegator, conditions, token, ..] => (delegator, conditions, token),
+        _ => return Err(anyhow!("invalid delegation tag")),
+    };
+
+    check_conditions(conditions, event)?;
+
+    // The delegator signs sha256("nostr:delegation:<delegatee pubkey>:<conditions>")
+    let delegation = format!("nostr:delegation:{}:{conditions}", &event.pubkey);
+    let message = secp256k1::Message::from_slice(&hex::decode(sha256::digest(delegation))?)?;
+
+    let pubkey = XOnlyPublicKey::from_str(delegator).map_err(|_| anyhow!("invalid delegator pubkey"))?;
+    let signature = schnorr::Signature::from_str(token).map_err(|_| anyhow!("invalid delegation token"))?;
+
+    Secp256k1::verification_only().verify_schnorr(&signature, &message, &pubkey)
+        .map_err(|_| anyhow!("invalid delegation token"))?;
+
+    Ok(Some(delegator.to_lowercase()))
+}
+
+// Conditions are `&` separated `kind=<kind>`, `created_at<<time>` and `created_at><time>`.
+// Any listed kind is allowed
+fn check_conditions(conditions: &str, event: &Event) -> Result<()> {
+    let mut kinds = vec![];
+
+    for condition in conditions.split('&').filter(|condition| !condition.is_empty()) {
+        let invalid = || anyhow!("invalid delegation condition {condition}");
+
+        if let Some(kind) = condition.strip_prefix("kind=") {
+            kinds.push(kind.parse::<u64>().map_err(|_| invalid())?);
+        } else if let Some(before) = condition.strip_prefix("created_at<") {
+            if event.created_at >= before.parse::<u64>().map_err(|_| invalid())? {
+                return Err(anyhow!("delegation expired"))
+            }
+        } else if let Some(after) = condition.strip_prefix("created_at>") {
+            if event.created_at <= after.parse::<u64>().map_err(|_| invalid())? {
+                return Err(anyhow!("delegation not yet valid"))
+            }
+        } else {
+            return Err(invalid())
+        }
+    }
+
+    if !kinds.is_empty() && !kinds.contains(&event.kind) {
+        return Err(anyhow!("delegation doesn't allow kind {}", event.kind))
+    }
+
+    Ok(())
+}
+
+/// Pubkeys each account has authorised to have events mined on its behalf (and billed
+/// to it). Kept in memory, and saved to a JSON file when one is configured
+#[derive(Default)]
+pub struct GrantStore {
+    grants: Mutex<BTreeMap<String, BTreeSet<String>>>,
+    file: Option<PathBuf>,
+}
+
+impl GrantStore {
+
+    /// Load the configured grants file (created on the first grant)
+    pub fn open(file: Option<&Path>) -> Result<Self> {
+        let path = match file {
+            None => return Ok(Self::default()),
+            Some(path) => path,
+        };
+
+        let grants = match std::fs::read_to_string(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(anyhow!("Unable to read grants {path:?}: {e}")),
+            Ok(json) => serde_json::from_str(&json).map_err(|e| anyhow!("Invalid grants {path:?}: {e}"))?,
+        };
+
+        info!("Grants: {path:?}");
+
+        Ok(Self { grants: Mutex::new(grants), file: Some(path.to_path_buf()) })
+    }
+
+    pub fn is_granted(&self, pubkey: &str, grantee: &str) -> bool {
+        let grants = self.grants.lock().expect("grants lock poisoned");
+        grants.get(pubkey).is_some_and(|grantees| grantees.contains(grantee))
+    }
+
+    pub fn list(&self, pubkey: &str) -> Vec<String> {
+        let grants = self.grants.lock().expect("grants lock poisoned");
+        grants.get(pubkey).map(|grantees| grantees.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Authorise `grantee`'s events to be mined for `pubkey`
+    pub fn grant(&self, pubkey: &str, grantee: &str) -> Result<()> {
+        if XOnlyPublicKey::from_str(grantee).is_err() {
+            return Err(anyhow!("invalid pubkey {grantee}"))
+        }
+
+        let mut grants = self.grants.lock().expect("grants lock poisoned");
+        grants.entry(pubkey.to_string()).or_default().insert(grantee.to_lowercase());
+        self.save(&grants)
+    }
+
+    /// Returns false if `grantee` wasn't authorised
+    pub fn revoke(&self, pubkey: &str, grantee: &str) -> Result<bool> {
+        let mut grants = self.grants.lock().expect("grants lock poisoned");
+
+        let revoked = grants.get_mut(pubkey).is_some_and(|grantees| grantees.remove(&grantee.to_lowercase()));
+        grants.retain(|_, grantees| !grantees.is_empty());
+
+        self.save(&grants)?;
+        Ok(revoked)
+    }
+
+    fn save(&self, grants: &BTreeMap<String, BTreeSet<String>>) -> Result<()> {
+        let path = match &self.file {
+            None => return Ok(()),
+            Some(path) => path,
+        };
+
+        // Replace the file in one go, so a failed write doesn't lose every grant
+        let saving_path = path.with_extension("saving");
+        std::fs::write(&saving_path, serde_json::to_string_pretty(grants)?)
+            .and_then(|_| std::fs::rename(&saving_path, path))
+            .map_err(|e| anyhow!("Unable to save grants {path:?}: {e}"))
+    }
+}
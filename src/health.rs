@@ -0,0 +1,139 @@
+use anyhow::{anyhow, Result};
+use crate::get_timestamp;
+use crate::payment::{check_ledger, check_payment_provider};
+use crate::pow::{check_nip13, get_content_id, generate_pow, MiningControl, MiningOptions};
+use crate::state::AppState;
+use nostr_rs_relay::event::Event;
+use nostr_rust::events::EventPrepare;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::{Arc, OnceLock};
+use tokio::time::{timeout, Duration};
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+// Low enough to mine in milliseconds, high enough to exercise the miner
+const SELF_TEST_DIFFICULTY: u16 = 8;
+const SELF_TEST_PUBKEY: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+const SELF_TEST_CONTENT: &str = "nostr pow service self-test";
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Startup self-test result, set once it has run
+#[derive(Default)]
+pub struct Health {
+    self_test: OnceLock<Result<(), String>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    /// Check name to "ok" or the failure reason
+    pub checks: BTreeMap<&'static str, String>,
+}
+
+impl Health {
+
+    /// Mine a known low difficulty event and verify it per NIP-13
+    pub async fn run_self_test(&self) {
+        let result = self_test().await.map_err(|e| e.to_string());
+
+        match &result {
+            Ok(_) => info!("Self-test passed"),
+            Err(e) => error!("Self-test failed: {e}"),
+        }
+
+        let _ = self.self_test.set(result);
+    }
+
+    fn self_test_status(&self) -> Result<()> {
+        match self.self_test.get() {
+            None => Err(anyhow!("not run yet")),
+            Some(Err(e)) => Err(anyhow!("{e}")),
+            Some(Ok(_)) => Ok(()),
+        }
+    }
+}
+
+async fn self_test() -> Result<()> {
+    let event_prepare = EventPrepare {
+        pub_key: SELF_TEST_PUBKEY.to_string(),
+        created_at: get_timestamp(),
+        kind: 1,
+        tags: vec![],
+        content: SELF_TEST_CONTENT.to_string(),
+    };
+
+    // Mining only needs the event fields, so it doesn't need to be signed
+    let event: Event = serde_json::from_value(json!({
+        "id": get_content_id(&event_prepare),
+        "pubkey": SELF_TEST_PUBKEY,
+        "created_at": event_prepare.created_at,
+        "kind": 1,
+        "tags": [],
+        "content": SELF_TEST_CONTENT,
+        "sig": "",
+    }))?;
+
+    let mined_event = generate_pow(SELF_TEST_DIFFICULTY, event, Arc::new(MiningControl::default()), MiningOptions::default()).await?;
+
+    check_nip13(&mined_event, SELF_TEST_DIFFICULTY)?;
+
+    Ok(())
+}
+
+/// Run every readiness check against the current config
+pub async fn readiness(state: &AppState) -> Readiness {
+    let app_config = state.config.current().await;
+
+    let queue_depth = state.mining_queue.waiting();
+    let queue_check = match queue_depth <= app_config.max_queue_depth {
+        true => Ok(()),
+        false => Err(anyhow!("{queue_depth} jobs queued (max {})", app_config.max_queue_depth)),
+    };
+
+    let checks = [
+        ("ledger", with_timeout(check_ledger()).await),
+        ("payment_provider", with_timeout(check_payment_provider()).await),
+        ("queue_depth", queue_check),
+        ("self_test", state.health.self_test_status()),
+    ];
+
+    let ready = checks.iter().all(|(_, result)| result.is_ok());
+    let checks = checks.into_iter()
+        .map(|(name, result)| (name, result.err().map_or("ok".to_string(), |e| e.to_string())))
+        .collect();
+
+    Readiness { ready, checks }
+}
+
+async fn with_timeout(check: impl std::future::Future<Output = Result<()>>) -> Result<()> {
+    timeout(CHECK_TIMEOUT, check).await.map_err(|_| anyhow!("timed out"))?
+}
+
+/// GET /healthz (process is alive) and GET /readyz (ready to take jobs, 503 otherwise)
+pub fn routes(state: Arc<AppState>) -> BoxedFilter<(Response,)> {
+
+    let healthz_route = warp::path!("healthz")
+        .and(warp::get())
+        .map(|| warp::reply::json(&json!({ "status": "ok" })).into_response());
+
+    let readyz_route = warp::path!("readyz")
+        .and(warp::get())
+        .and(warp::any().map(move || Arc::clone(&state)))
+        .and_then(|state: Arc<AppState>| async move {
+            let readiness = readiness(&state).await;
+            let status = match readiness.ready {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            Ok::<_, Infallible>(warp::reply::with_status(warp::reply::json(&readiness), status).into_response())
+        });
+
+    healthz_route
+        .or(readyz_route).unify()
+        .boxed()
+}
@@ -0,0 +1,396 @@
+use anyhow::{anyhow, Result};
+use crate::admin::{handle_admin_request, AdminRequest};
+use crate::audit::{AuditAction, AuditRecord};
+use crate::auth::{verify_http_auth, HttpAuthRequest};
+use crate::commands::PowOptions;
+use crate::config::AppConfig;
+use crate::jobs::{prepare_job, run_job, Job, JobRejection, JobStatus};
+use crate::metrics::metrics;
+use crate::state::AppState;
+use futures::Stream;
+use nostr_rs_relay::event::Event;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::time::{interval, Duration};
+use warp::filters::BoxedFilter;
+use warp::filters::sse;
+use warp::http::{Method, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+use warp_real_ip::real_ip;
+
+const SSE_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// POST /pow body
+#[derive(Deserialize, Debug)]
+pub struct PowSubmission {
+    pub event: Event,
+    pub target_pow: u16,
+    #[serde(flatten)]
+    pub options: PowOptions,
+}
+
+/// Request details needed for NIP-98 HTTP Auth
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub path: FullPath,
+    pub host: Option<String>,
+    pub authorization: Option<String>,
+    pub real_ip: Option<IpAddr>,
+}
+
+/// REST API for PoW jobs, for clients that can't hold a websocket open
+///
+///   POST   /pow                  {"event": {...}, "target_pow": 20} -> 202 job
+///   GET    /pow/{job_id}         -> job status (and event once completed)
+///   GET    /pow/{job_id}/events  -> Server-Sent Events stream of job progress
+///   DELETE /pow/{job_id}         -> cancel (refunded if paid)
+///   POST   /admin                {"method": ..., "params": {...}} -> result (admin pubkeys only)
+///   GET    /grants               -> pubkeys whose events may be mined for (and billed to) you
+///   PUT    /grants/{pubkey}      -> grant pubkey (needs delegated requests enabled)
+///   DELETE /grants/{pubkey}      -> revoke pubkey
+///
+/// All requests are authenticated with NIP-98 HTTP Auth. As browsers can't set
+/// headers on an EventSource, the events stream also accepts the base64 NIP-98
+/// event as an `auth` query parameter.
+pub fn routes(state: Arc<AppState>, proxy_addrs: Vec<IpAddr>, max_body_size: u64) -> BoxedFilter<(Response,)> {
+
+    let state_filter = warp::any().map(move || Arc::clone(&state));
+
+    let request_filter = warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("host"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(real_ip(proxy_addrs))
+        .map(|method, path, host, authorization, real_ip| HttpRequest { method, path, host, authorization, real_ip });
+
+    let submit_route = warp::path!("pow")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_body_size))
+        .and(warp::body::bytes())
+        .and(request_filter.clone())
+        .and(state_filter.clone())
+        .and_then(submit_job);
+
+    let status_route = warp::path!("pow" / String)
+        .and(warp::get())
+        .and(request_filter.clone())
+        .and(state_filter.clone())
+        .and_then(job_status);
+
+    let events_route = warp::path!("pow" / String / "events")
+        .and(warp::get())
+        .and(request_filter.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(state_filter.clone())
+        .and_then(job_events);
+
+    let cancel_route = warp::path!("pow" / String)
+        .and(warp::delete())
+        .and(request_filter.clone())
+        .and(state_filter.clone())
+        .and_then(cancel_job);
+
+    let admin_route = warp::path!("admin")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_body_size))
+        .and(warp::body::bytes())
+        .and(request_filter.clone())
+        .and(state_filter.clone())
+        .and_then(admin_request);
+
+    let list_grants_route = warp::path!("grants")
+        .and(warp::get())
+        .and(request_filter.clone())
+        .and(state_filter.clone())
+        .and_then(list_grants);
+
+    let grant_route = warp::path!("grants" / String)
+        .and(warp::put())
+        .and(request_filter.clone())
+        .and(state_filter.clone())
+        .and_then(grant);
+
+    let revoke_route = warp::path!("grants" / String)
+        .and(warp::delete())
+        .and(request_filter)
+        .and(state_filter)
+        .and_then(revoke_grant);
+
+    submit_route
+        .or(status_route).unify()
+        .or(events_route).unify()
+        .or(cancel_route).unify()
+        .or(admin_route).unify()
+        .or(list_grants_route).unify()
+        .or(grant_route).unify()
+        .or(revoke_route).unify()
+        .boxed()
+}
+
+async fn submit_job(body: Bytes, request: HttpRequest, state: Arc<AppState>) -> Result<Response, Infallible> {
+
+    let app_config = state.config.current().await;
+
+    let pubkey = match authenticate(&state, &app_config, &request, Some(&body)) {
+        Ok(pubkey) => pubkey,
+        Err(e) => return Ok(error_reply(StatusCode::UNAUTHORIZED, &format!("auth-required: {e}"))),
+    };
+
+    let submission: PowSubmission = match serde_json::from_slice(&body) {
+        Ok(submission) => submission,
+        Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, &format!("invalid: {e}"))),
+    };
+
+    let prepared_job = match prepare_job(&state, &app_config, &pubkey, request.real_ip, submission.target_pow, submission.event, submission.options).await {
+        Ok(prepared_job) => prepared_job,
+        Err(rejection) => {
+            let status = match rejection {
+                JobRejection::Invalid(_) => StatusCode::BAD_REQUEST,
+                JobRejection::Blocked(_) => StatusCode::FORBIDDEN,
+                JobRejection::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+                JobRejection::PaymentRequired => StatusCode::PAYMENT_REQUIRED,
+            };
+            return Ok(error_reply(status, &rejection.to_string()))
+        },
+    };
+
+    let summary = prepared_job.job.summary();
+
+    tokio::spawn(async move {
+        run_job(&state, &app_config, prepared_job).await;
+    });
+
+    Ok(warp::reply::with_status(warp::reply::json(&summary), StatusCode::ACCEPTED).into_response())
+}
+
+async fn job_status(job_id: String, request: HttpRequest, state: Arc<AppState>) -> Result<Response, Infallible> {
+
+    let app_config = state.config.current().await;
+
+    let pubkey = match authenticate(&state, &app_config, &request, None) {
+        Ok(pubkey) => pubkey,
+        Err(e) => return Ok(error_reply(StatusCode::UNAUTHORIZED, &format!("auth-required: {e}"))),
+    };
+
+    match state.jobs.get(&job_id) {
+        Some(job) if job.pubkey == pubkey => Ok(warp::reply::json(&job.summary()).into_response()),
+        _ => Ok(error_reply(StatusCode::NOT_FOUND, "job not found")),
+    }
+}
+
+async fn job_events(job_id: String, mut request: HttpRequest, query: HashMap<String, String>, state: Arc<AppState>) -> Result<Response, Infallible> {
+
+    let app_config = state.config.current().await;
+
+    if request.authorization.is_none() {
+        request.authorization = query.get("auth").map(|auth| format!("Nostr {auth}"));
+    }
+
+    let pubkey = match authenticate(&state, &app_config, &request, None) {
+        Ok(pubkey) => pubkey,
+        Err(e) => return Ok(error_reply(StatusCode::UNAUTHORIZED, &format!("auth-required: {e}"))),
+    };
+
+    match state.jobs.get(&job_id) {
+        Some(job) if job.pubkey == pubkey => {
+            Ok(sse::reply(sse::keep_alive().stream(job_event_stream(job))).into_response())
+        },
+        _ => Ok(error_reply(StatusCode::NOT_FOUND, "job not found")),
+    }
+}
+
+// Emits queued, started, progress (while running) and a final completed,
+// failed or cancelled event, then ends the stream
+fn job_event_stream(job: Arc<Job>) -> impl Stream<Item = Result<sse::Event, Infallible>> {
+    let status_rx = job.subscribe();
+    let progress_interval = interval(SSE_PROGRESS_INTERVAL);
+
+    futures::stream::unfold(Some((job, status_rx, progress_interval, None)), |stream_state| async move {
+        let (job, mut status_rx, mut progress_interval, mut sent_status) = stream_state?;
+
+        loop {
+            let status = job.status();
+            let status_name = match status {
+                JobStatus::Queued => "queued",
+                JobStatus::Running => "started",
+                JobStatus::Completed { .. } => "completed",
+                JobStatus::Failed { .. } => "failed",
+                JobStatus::Cancelled => "cancelled",
+            };
+
+            if sent_status != Some(status_name) {
+                let event = sse::Event::default().event(status_name).json_data(job.summary()).ok()?;
+                let next_state = (!status.is_finished()).then_some((job, status_rx, progress_interval, Some(status_name)));
+                return Some((Ok(event), next_state))
+            }
+
+            tokio::select! {
+                changed = status_rx.changed() => {
+                    if changed.is_err() {
+                        return None
+                    }
+                },
+
+                _ = progress_interval.tick() => {
+                    if let JobStatus::Running = status {
+                        let event = sse::Event::default().event("progress").json_data(job.control.progress(job.target_pow)).ok()?;
+                        sent_status = Some(status_name);
+                        return Some((Ok(event), Some((job, status_rx, progress_interval, sent_status))))
+                    }
+                },
+            }
+        }
+    })
+}
+
+async fn cancel_job(job_id: String, request: HttpRequest, state: Arc<AppState>) -> Result<Response, Infallible> {
+
+    let app_config = state.config.current().await;
+
+    let pubkey = match authenticate(&state, &app_config, &request, None) {
+        Ok(pubkey) => pubkey,
+        Err(e) => return Ok(error_reply(StatusCode::UNAUTHORIZED, &format!("auth-required: {e}"))),
+    };
+
+    let job = match state.jobs.get(&job_id) {
+        Some(job) if job.pubkey == pubkey => job,
+        _ => return Ok(error_reply(StatusCode::NOT_FOUND, "job not found")),
+    };
+
+    if !job.cancel() {
+        return Ok(error_reply(StatusCode::CONFLICT, "job already finished"))
+    }
+
+    info!("Job {job_id} cancelled by {pubkey}");
+
+    Ok(warp::reply::with_status(warp::reply::json(&job.summary()), StatusCode::ACCEPTED).into_response())
+}
+
+async fn admin_request(body: Bytes, request: HttpRequest, state: Arc<AppState>) -> Result<Response, Infallible> {
+
+    let app_config = state.config.current().await;
+
+    let pubkey = match authenticate(&state, &app_config, &request, Some(&body)) {
+        Ok(pubkey) => pubkey,
+        Err(e) => return Ok(error_reply(StatusCode::UNAUTHORIZED, &format!("auth-required: {e}"))),
+    };
+
+    if !app_config.is_admin(&pubkey) {
+        return Ok(error_reply(StatusCode::FORBIDDEN, "restricted: admin only"))
+    }
+
+    let admin_request: AdminRequest = match serde_json::from_slice(&body) {
+        Ok(admin_request) => admin_request,
+        Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, &format!("invalid: {e}"))),
+    };
+
+    match handle_admin_request(&state, &app_config, &pubkey, admin_request).await {
+        Ok(result) => Ok(warp::reply::json(&result).into_response()),
+        Err(e) => Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string())),
+    }
+}
+
+async fn list_grants(request: HttpRequest, state: Arc<AppState>) -> Result<Response, Infallible> {
+
+    let app_config = state.config.current().await;
+
+    let pubkey = match authenticate(&state, &app_config, &request, None) {
+        Ok(pubkey) => pubkey,
+        Err(e) => return Ok(error_reply(StatusCode::UNAUTHORIZED, &format!("auth-required: {e}"))),
+    };
+
+    Ok(warp::reply::json(&json!({ "grants": state.grants.list(&pubkey) })).into_response())
+}
+
+async fn grant(grantee: String, request: HttpRequest, state: Arc<AppState>) -> Result<Response, Infallible> {
+
+    let app_config = state.config.current().await;
+
+    let pubkey = match authenticate(&state, &app_config, &request, None) {
+        Ok(pubkey) => pubkey,
+        Err(e) => return Ok(error_reply(StatusCode::UNAUTHORIZED, &format!("auth-required: {e}"))),
+    };
+
+    if !app_config.delegation.enabled {
+        return Ok(error_reply(StatusCode::FORBIDDEN, "restricted: delegated requests are disabled"))
+    }
+
+    if let Err(e) = state.grants.grant(&pubkey, &grantee) {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &format!("invalid: {e}")))
+    }
+
+    info!("{pubkey} granted {grantee}");
+
+    Ok(warp::reply::json(&json!({ "grants": state.grants.list(&pubkey) })).into_response())
+}
+
+async fn revoke_grant(grantee: String, request: HttpRequest, state: Arc<AppState>) -> Result<Response, Infallible> {
+
+    let app_config = state.config.current().await;
+
+    let pubkey = match authenticate(&state, &app_config, &request, None) {
+        Ok(pubkey) => pubkey,
+        Err(e) => return Ok(error_reply(StatusCode::UNAUTHORIZED, &format!("auth-required: {e}"))),
+    };
+
+    match state.grants.revoke(&pubkey, &grantee) {
+        Ok(true) => {
+            info!("{pubkey} revoked {grantee}");
+            Ok(warp::reply::json(&json!({ "grants": state.grants.list(&pubkey) })).into_response())
+        },
+        Ok(false) => Ok(error_reply(StatusCode::NOT_FOUND, "grant not found")),
+        Err(e) => Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, &format!("error: {e}"))),
+    }
+}
+
+// NIP-98 HTTP Auth. Returns the authenticated pubkey
+fn authenticate(state: &AppState, app_config: &AppConfig, request: &HttpRequest, body: Option<&[u8]>) -> Result<String> {
+
+    let authorization = request.authorization.as_deref()
+        .ok_or_else(|| anyhow!("missing Authorization header"))?;
+
+    let urls = request_urls(app_config, request);
+
+    let result = verify_http_auth(app_config, authorization, &HttpAuthRequest {
+        urls: &urls,
+        method: request.method.as_str(),
+        body,
+        payload_required: body.is_some(),
+        seen_events: Some(&state.http_auth_events),
+    });
+
+    let audit_record = match &result {
+        Ok(pubkey) => AuditRecord { pubkey: Some(pubkey.clone()), ..AuditRecord::new(AuditAction::Auth, "ok") },
+        Err(e) => {
+            metrics().auth_failures.with_label_values(&["http"]).inc();
+            AuditRecord { message: Some(e.to_string()), ..AuditRecord::new(AuditAction::Auth, "failed") }
+        },
+    };
+    state.audit.record(AuditRecord { ip: request.real_ip, ..audit_record });
+
+    result
+}
+
+// Absolute URLs the request could have been made to. Behind a proxy we can't
+// know the scheme, so without a configured public_url both are accepted.
+fn request_urls(app_config: &AppConfig, request: &HttpRequest) -> Vec<String> {
+    let path = request.path.as_str();
+
+    match (&app_config.public_url, &request.host) {
+        (Some(public_url), _) => vec![format!("{}{path}", public_url.trim_end_matches('/'))],
+        (None, Some(host)) => vec![format!("https://{host}{path}"), format!("http://{host}{path}")],
+        (None, None) => vec![],
+    }
+}
+
+fn error_reply(status: StatusCode, message: &str) -> Response {
+    warp::reply::with_status(warp::reply::json(&json!({ "error": message })), status).into_response()
+}
@@ -0,0 +1,607 @@
+use anyhow::anyhow;
+use crate::audit::{AuditAction, AuditRecord};
+use crate::calibration::JobEta;
+use crate::commands::PowOptions;
+use crate::config::AppConfig;
+use crate::get_timestamp;
+use crate::metrics::{difficulty_bucket, metrics};
+use crate::payment::{credit_account, debt_account, payment_required, price_for_difficulty};
+use crate::pow::{event_with_nonce, generate_pow, validate_pow_request, MiningControl, MiningOptions, MiningProgress};
+use crate::profile::{JobQuotaGuard, Profile};
+use crate::registry::MinedRecord;
+use crate::signer::{sign_with_bunker, BunkerUri};
+use crate::state::AppState;
+use nostr_rs_relay::event::Event;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+use uuid::Uuid;
+
+// Finished jobs are kept this long so HTTP clients can fetch the result
+const FINISHED_JOB_RETENTION: Duration = Duration::from_secs(3600);
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    /// `difficulty` is what was actually mined, which can be below the target for
+    /// jobs that ran out of time (or hit their deadline). `signed` when a NIP-46
+    /// remote signer signed the event
+    Completed {
+        event: Box<Event>,
+        difficulty: u16,
+        signed: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        signer_error: Option<String>,
+    },
+    Failed { message: String },
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Completed { .. } | JobStatus::Failed { .. } | JobStatus::Cancelled)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Completed { .. } => "completed",
+            JobStatus::Failed { .. } => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Why a PoW request was turned away before mining
+#[derive(Debug)]
+pub enum JobRejection {
+    Invalid(anyhow::Error),
+    Blocked(anyhow::Error),
+    RateLimited(anyhow::Error),
+    PaymentRequired,
+}
+
+impl JobRejection {
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobRejection::Invalid(_) => "invalid",
+            JobRejection::Blocked(_) => "blocked",
+            JobRejection::RateLimited(_) => "rate_limited",
+            JobRejection::PaymentRequired => "payment_required",
+        }
+    }
+}
+
+impl fmt::Display for JobRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobRejection::Invalid(e) => write!(f, "invalid pow request: {e}"),
+            JobRejection::Blocked(e) => write!(f, "{e}"),
+            JobRejection::RateLimited(e) => write!(f, "{e}"),
+            JobRejection::PaymentRequired => write!(f, "out of credit"),
+        }
+    }
+}
+
+/// A single PoW request, from either the websocket or HTTP API
+pub struct Job {
+    pub id: String,
+    pub pubkey: String,
+    pub event_id: String,
+    pub real_ip: Option<IpAddr>,
+    pub requested_pow: u16,
+    pub target_pow: u16,
+    /// Lowest difficulty the client accepts if the job runs out of time
+    pub min_pow: Option<u16>,
+    /// Mining time allowed, from when mining starts (None is unlimited). For best effort
+    /// jobs this is the client's deadline
+    pub time_budget: Option<Duration>,
+    pub best_effort: bool,
+    /// Mined with the client's created_at rather than bumped to the mining time
+    pub preserve_created_at: bool,
+    /// NIP-46 bunker to sign the mined event
+    pub bunker: Option<BunkerUri>,
+    /// Served from the mined event registry, without mining (or charging) again
+    pub cached: bool,
+    pub eta: Option<JobEta>,
+    pub profile: Profile,
+    pub payment_required: bool,
+    pub created_at: u64,
+    pub control: Arc<MiningControl>,
+    // Current status, and when the job finished
+    status: watch::Sender<(JobStatus, Option<Instant>)>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JobSummary {
+    pub job_id: String,
+    pub pubkey: String,
+    pub event_id: String,
+    pub target_pow: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_pow: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_budget_sec: Option<f64>,
+    pub best_effort: bool,
+    pub preserve_created_at: bool,
+    pub cached: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta: Option<JobEta>,
+    pub created_at: u64,
+    pub progress: MiningProgress,
+    #[serde(flatten)]
+    pub status: JobStatus,
+}
+
+impl Job {
+    pub fn status(&self) -> JobStatus {
+        self.status.borrow().0.clone()
+    }
+
+    /// Notified on every status change
+    pub fn subscribe(&self) -> watch::Receiver<(JobStatus, Option<Instant>)> {
+        self.status.subscribe()
+    }
+
+    fn set_status(&self, status: JobStatus) {
+        let finished_at = status.is_finished().then(Instant::now);
+        self.status.send_replace((status, finished_at));
+    }
+
+    fn finished_for(&self) -> Option<Duration> {
+        self.status.borrow().1.map(|finished_at| finished_at.elapsed())
+    }
+
+    /// Ask the miner to stop. Returns false if the job had already finished
+    pub fn cancel(&self) -> bool {
+        if self.status().is_finished() {
+            return false
+        }
+
+        self.control.cancel();
+        true
+    }
+
+    fn audit_record(&self, action: AuditAction, outcome: &str) -> AuditRecord {
+        AuditRecord {
+            pubkey: Some(self.pubkey.clone()),
+            ip: self.real_ip,
+            event_id: Some(self.event_id.clone()),
+            job_id: Some(self.id.clone()),
+            difficulty: Some(self.target_pow),
+            ..AuditRecord::new(action, outcome)
+        }
+    }
+
+    pub fn summary(&self) -> JobSummary {
+        JobSummary {
+            job_id: self.id.clone(),
+            pubkey: self.pubkey.clone(),
+            event_id: self.event_id.clone(),
+            target_pow: self.target_pow,
+            min_pow: self.min_pow,
+            time_budget_sec: self.time_budget.map(|budget| budget.as_secs_f64()),
+            best_effort: self.best_effort,
+            preserve_created_at: self.preserve_created_at,
+            cached: self.cached,
+            eta: self.eta.clone(),
+            created_at: self.created_at,
+            progress: self.control.progress(self.target_pow),
+            status: self.status(),
+        }
+    }
+}
+
+/// All current jobs, and recently finished ones
+#[derive(Default)]
+pub struct JobRegistry {
+    jobs: RwLock<HashMap<String, Arc<Job>>>,
+}
+
+impl JobRegistry {
+    pub fn insert(&self, job: Arc<Job>) {
+        let mut jobs = self.jobs.write().expect("job registry lock poisoned");
+
+        jobs.retain(|_, job| job.finished_for().map_or(true, |finished_for| finished_for < FINISHED_JOB_RETENTION));
+        jobs.insert(job.id.clone(), job);
+    }
+
+    pub fn get(&self, job_id: &str) -> Option<Arc<Job>> {
+        self.jobs.read().expect("job registry lock poisoned").get(job_id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Arc<Job>> {
+        self.jobs.read().expect("job registry lock poisoned").values().cloned().collect()
+    }
+}
+
+/// A job that passed every check (and was paid for), ready to be mined. Or one
+/// already mined for this pubkey, to be returned from the registry
+pub struct PreparedJob {
+    pub job: Arc<Job>,
+    event: Event,
+    cached: Option<MinedRecord>,
+    _quota: Option<JobQuotaGuard>,
+}
+
+/// Validate, apply policy and admission, reserve quota and take payment for a PoW request
+pub async fn prepare_job(
+        state: &AppState,
+        app_config: &AppConfig,
+        pubkey: &str,
+        real_ip: Option<IpAddr>,
+        requested_pow: u16,
+        event: Event,
+        options: PowOptions,
+    ) -> Result<PreparedJob, JobRejection> {
+
+    let event_id = event.id.clone();
+
+    let result = admit_and_charge(state, app_config, pubkey, real_ip, requested_pow, event, options).await;
+
+    if let Err(rejection) = &result {
+        state.audit.record(AuditRecord {
+            message: Some(rejection.to_string()),
+            pubkey: Some(pubkey.to_string()),
+            ip: real_ip,
+            event_id: Some(event_id),
+            difficulty: Some(requested_pow),
+            ..AuditRecord::new(AuditAction::JobRejected, rejection.name())
+        });
+    }
+
+    result
+}
+
+async fn admit_and_charge(
+        state: &AppState,
+        app_config: &AppConfig,
+        pubkey: &str,
+        real_ip: Option<IpAddr>,
+        requested_pow: u16,
+        event: Event,
+        options: PowOptions,
+    ) -> Result<PreparedJob, JobRejection> {
+
+    if state.peers.is_banned(Some(pubkey), real_ip).await {
+        return Err(JobRejection::Blocked(anyhow!("blocked: banned")))
+    }
+
+    let profile = state.profiles.resolve(app_config, pubkey).await;
+
+    validate_pow_request(app_config, &state.grants, profile, requested_pow, &event, pubkey).map_err(JobRejection::Invalid)?;
+
+    if let Some(min_pow) = options.min_pow {
+        if min_pow > requested_pow {
+            return Err(JobRejection::Invalid(anyhow!("restricted: min_pow must not be above the target difficulty")))
+        }
+    }
+
+    if let Some(deadline_sec) = options.deadline_sec {
+        if options.min_pow.is_none() {
+            return Err(JobRejection::Invalid(anyhow!("restricted: min_pow is required with deadline_sec")))
+        }
+        if deadline_sec == 0 {
+            return Err(JobRejection::Invalid(anyhow!("restricted: deadline_sec must be above 0")))
+        }
+        if app_config.job_time_budget_sec > 0 && deadline_sec > app_config.job_time_budget_sec {
+            return Err(JobRejection::Invalid(anyhow!("restricted: deadline_sec must be at most {}", app_config.job_time_budget_sec)))
+        }
+    }
+
+    // Checked before taking payment, as the bunker relays are only connected to once mined
+    if let Some(bunker) = &options.bunker {
+        if let Some(Err(e)) = bunker.relays.iter().map(|relay| app_config.relay_access.check_url(relay)).find(Result::is_err) {
+            return Err(JobRejection::Invalid(anyhow!("restricted: bunker {e}")))
+        }
+    }
+
+    // Don't take jobs we don't expect to finish within the SLA. Best effort jobs
+    // stop at their deadline, so they can ask for more
+    if app_config.calibration.clamp_max_pow_difficulty && options.deadline_sec.is_none() {
+        if let Some(max_pow) = state.calibration.max_difficulty(&app_config.calibration) {
+            if requested_pow > max_pow {
+                let sla_sec = app_config.calibration.sla_sec;
+                return Err(JobRejection::Invalid(anyhow!("restricted: target difficulty must be at most {max_pow} to finish within {sla_sec} seconds")))
+            }
+        }
+    }
+
+    if let Err(e) = app_config.event_policy.admit(&event, pubkey, real_ip, requested_pow).await {
+        info!("Policy rejected {} for {pubkey}: {e}", &event.id);
+        return Err(JobRejection::Blocked(e))
+    }
+
+    // The admission server (if configured) may deny, or change the difficulty we mine
+    let target_pow = match &app_config.admission {
+        None => requested_pow,
+        Some(admission) => {
+            let adjusted = admission.admit(&event, pubkey, real_ip, requested_pow).await.map_err(|e| {
+                info!("Admission rejected {} for {pubkey}: {e}", &event.id);
+                JobRejection::Blocked(e)
+            })?;
+
+            // Keep an adjusted difficulty within the profile's range (and the calibrated max,
+            // as for requests), so the admission server can't queue an unbounded job
+            let mut max_pow = profile.max_pow_difficulty;
+            if app_config.calibration.clamp_max_pow_difficulty && options.deadline_sec.is_none() {
+                if let Some(calibrated_max_pow) = state.calibration.max_difficulty(&app_config.calibration) {
+                    max_pow = max_pow.min(calibrated_max_pow);
+                }
+            }
+
+            let clamped = adjusted.min(max_pow).max(profile.min_pow_difficulty);
+            if clamped != adjusted {
+                warn!("Admission server difficulty {adjusted} for {} clamped to {clamped}", &event.id);
+            }
+            clamped
+        },
+    };
+
+    // Already mined this event for them, so return that rather than mining (and charging) again.
+    // Only once the admission server has allowed it, as it may now deny the pubkey or event
+    let cached = state.registry.cached(pubkey, &event.id, target_pow)
+        .filter(|record| !options.preserve_created_at || record.event.created_at == event.created_at);
+
+    if let Some(record) = cached {
+        info!("Returning {} mined by job {} for {pubkey}", &record.event_id, &record.job_id);
+
+        let job = Arc::new(Job {
+            id: Uuid::new_v4().to_string(),
+            pubkey: pubkey.to_string(),
+            event_id: event.id.clone(),
+            real_ip,
+            requested_pow,
+            target_pow,
+            min_pow: None,
+            time_budget: None,
+            best_effort: false,
+            preserve_created_at: options.preserve_created_at,
+            bunker: options.bunker,
+            cached: true,
+            eta: None,
+            profile: profile.clone(),
+            payment_required: false,
+            created_at: get_timestamp(),
+            control: Arc::new(MiningControl::default()),
+            status: watch::channel((JobStatus::Queued, None)).0,
+        });
+
+        state.jobs.insert(Arc::clone(&job));
+
+        return Ok(PreparedJob { job, event, cached: Some(record), _quota: None })
+    }
+
+    // Held until this job is finished mining
+    let quota = state.profiles.start_job(pubkey, profile).map_err(|e| {
+        metrics().rate_limited.inc();
+        JobRejection::RateLimited(e)
+    })?;
+
+    let payment_required = payment_required(profile);
+
+    if payment_required {
+        let audit_record = |action, outcome: &str| AuditRecord {
+            pubkey: Some(pubkey.to_string()),
+            ip: real_ip,
+            event_id: Some(event.id.clone()),
+            difficulty: Some(target_pow),
+            ..AuditRecord::new(action, outcome)
+        };
+
+        let price_sat = price_for_difficulty(&app_config.pricing, profile, target_pow);
+        state.audit.record(AuditRecord { amount_sat: Some(price_sat), ..audit_record(AuditAction::Quote, "ok") });
+
+        match debt_account(&app_config.pricing, profile, pubkey, target_pow, &event.id).await {
+            Ok(amount_sat) => {
+                state.audit.record(AuditRecord { amount_sat: Some(amount_sat), ..audit_record(AuditAction::Debit, "ok") });
+            },
+            Err(e) => {
+                state.audit.record(AuditRecord {
+                    message: Some(e.to_string()),
+                    amount_sat: Some(price_sat),
+                    ..audit_record(AuditAction::Debit, "failed")
+                });
+                return Err(JobRejection::PaymentRequired)
+            },
+        }
+    }
+
+    let job = Arc::new(Job {
+        id: Uuid::new_v4().to_string(),
+        pubkey: pubkey.to_string(),
+        event_id: event.id.clone(),
+        real_ip,
+        requested_pow,
+        target_pow,
+        min_pow: options.min_pow.map(|min_pow| min_pow.min(target_pow)),
+        time_budget: match options.deadline_sec {
+            Some(deadline_sec) => Some(Duration::from_secs(deadline_sec)),
+            None => state.calibration.time_budget(target_pow, app_config.job_time_budget_sec),
+        },
+        best_effort: options.deadline_sec.is_some(),
+        preserve_created_at: options.preserve_created_at,
+        bunker: options.bunker,
+        cached: false,
+        eta: state.calibration.eta(target_pow),
+        profile: profile.clone(),
+        payment_required,
+        created_at: get_timestamp(),
+        control: Arc::new(MiningControl::default()),
+        status: watch::channel((JobStatus::Queued, None)).0,
+    });
+
+    state.jobs.insert(Arc::clone(&job));
+
+    Ok(PreparedJob { job, event, cached: None, _quota: Some(quota) })
+}
+
+/// Wait for a miner, mine the event, and refund the job if it failed or was cancelled
+/// (or the difference, if it mined less than the target). Mined events are added to the registry
+pub async fn run_job(state: &AppState, app_config: &AppConfig, prepared: PreparedJob) -> JobStatus {
+    let job = Arc::clone(&prepared.job);
+
+    if let Some(record) = &prepared.cached {
+        let status = complete_job(app_config, &job, record.event.clone(), record.difficulty).await;
+        metrics().job_finished(&status, job.target_pow);
+        state.audit.record(AuditRecord {
+            message: Some(format!("cached from job {}", &record.job_id)),
+            ..job.audit_record(AuditAction::JobFinish, status.name())
+        });
+        job.set_status(status.clone());
+        return status
+    }
+
+    let mining_slot = tokio::select! {
+        mining_slot = state.mining_queue.acquire(job.profile.priority, app_config.mining_threads) => Some(mining_slot),
+        _ = job.control.cancelled() => None,
+    };
+
+    let result = match mining_slot {
+        None => Err(anyhow!("cancelled")),
+        Some(_mining_slot) => {
+            job.set_status(JobStatus::Running);
+            state.audit.record(job.audit_record(AuditAction::JobStart, "ok"));
+
+            info!("Generating target POW: {} for {:?}", job.target_pow, &prepared.event);
+            let started_at = Instant::now();
+
+            // Stop the miner once the job runs over its time budget
+            let budget_timer = job.time_budget.map(|time_budget| {
+                let control = Arc::clone(&job.control);
+                tokio::spawn(async move {
+                    tokio::time::sleep(time_budget).await;
+                    control.time_out();
+                })
+            });
+
+            let mining_options = MiningOptions { preserve_created_at: job.preserve_created_at, ..app_config.mining.clone() };
+            let result = generate_pow(job.target_pow, prepared.event.clone(), Arc::clone(&job.control), mining_options).await;
+
+            if let Some(budget_timer) = budget_timer {
+                budget_timer.abort();
+            }
+
+            state.calibration.record(&job.control.progress(job.target_pow));
+
+            if result.is_ok() {
+                metrics().mining_duration
+                    .with_label_values(&[difficulty_bucket(job.target_pow)])
+                    .observe(started_at.elapsed().as_secs_f64());
+            }
+            result
+        },
+    };
+
+    // Out of time (or at the deadline), so fall back to the best result if the client accepts it
+    let result = match result {
+        Ok(event) => Ok((event, job.target_pow)),
+        Err(_) if job.control.is_timed_out() => {
+            match job.control.best().filter(|best| job.min_pow.is_some_and(|min_pow| best.leading_zeros >= min_pow)) {
+                Some(best) => {
+                    info!("Job {} timed out. Returning best difficulty {}", &job.id, best.leading_zeros);
+                    let difficulty = best.leading_zeros;
+                    Ok((event_with_nonce(prepared.event.clone(), best), difficulty))
+                },
+                None => Err(anyhow!("timed out")),
+            }
+        },
+        Err(e) => Err(e),
+    };
+
+    let status = match result {
+        Ok((event, difficulty)) => {
+            // Charge for the difficulty actually mined
+            let mut price_sat = match job.payment_required {
+                true => price_for_difficulty(&app_config.pricing, &job.profile, job.target_pow),
+                false => 0,
+            };
+            if job.payment_required && difficulty < job.target_pow {
+                let amount_sat = price_sat.saturating_sub(price_for_difficulty(&app_config.pricing, &job.profile, difficulty));
+                refund(state, &job, amount_sat, Some(difficulty)).await;
+                price_sat -= amount_sat;
+            }
+
+            let status = complete_job(app_config, &job, event, difficulty).await;
+
+            if let JobStatus::Completed { event, .. } = &status {
+                state.registry.insert(MinedRecord {
+                    event_id: event.id.clone(),
+                    source_event_id: job.event_id.clone(),
+                    pubkey: job.pubkey.clone(),
+                    difficulty,
+                    price_sat,
+                    created_at: get_timestamp(),
+                    job_id: job.id.clone(),
+                    event: (**event).clone(),
+                }).await;
+            }
+
+            status
+        },
+
+        Err(e) => {
+            warn!("generate_pow failed. {} {} {} {e:?}", &job.pubkey, job.target_pow, &job.event_id);
+
+            if job.payment_required {
+                let amount_sat = price_for_difficulty(&app_config.pricing, &job.profile, job.target_pow);
+                refund(state, &job, amount_sat, None).await;
+            }
+
+            match (job.control.is_timed_out(), job.control.is_cancelled()) {
+                (true, _) => JobStatus::Failed { message: "timed out".to_string() },
+                (false, true) => JobStatus::Cancelled,
+                (false, false) => JobStatus::Failed { message: "request failed".to_string() },
+            }
+        },
+    };
+
+    metrics().job_finished(&status, job.target_pow);
+    state.audit.record(job.audit_record(AuditAction::JobFinish, status.name()));
+    job.set_status(status.clone());
+
+    status
+}
+
+// Sign the mined event with the job's remote signer (if any). The PoW is done
+// (and paid for) whether or not the remote signer signs it
+async fn complete_job(app_config: &AppConfig, job: &Job, event: Event, difficulty: u16) -> JobStatus {
+    let (event, signed, signer_error) = match &job.bunker {
+        None => (event, false, None),
+        Some(bunker) => match sign_with_bunker(bunker, &event, app_config.remote_signer_timeout, &app_config.relay_access).await {
+            Ok(signed_event) => (signed_event, true, None),
+            Err(e) => {
+                warn!("Remote signing failed for job {}: {e:?}", &job.id);
+                (event, false, Some(e.to_string()))
+            },
+        },
+    };
+
+    JobStatus::Completed { event: Box::new(event), difficulty, signed, signer_error }
+}
+
+// Credit the account that paid for a job, recording the refund (and the difficulty mined, for partial refunds)
+async fn refund(state: &AppState, job: &Job, amount_sat: u32, mined_difficulty: Option<u16>) {
+    let message = mined_difficulty.map(|difficulty| format!("mined difficulty {difficulty}"));
+
+    match credit_account(&job.pubkey, amount_sat, &job.event_id).await {
+        Ok(_) => {
+            state.audit.record(AuditRecord { message, amount_sat: Some(amount_sat), ..job.audit_record(AuditAction::Credit, "ok") });
+        },
+        Err(e) => {
+            error!("Refund failed for job {}: {e:?}", &job.id);
+            state.audit.record(AuditRecord {
+                message: Some(e.to_string()),
+                amount_sat: Some(amount_sat),
+                ..job.audit_record(AuditAction::Credit, "failed")
+            });
+        },
+    }
+}
@@ -1,19 +1,35 @@
 #[macro_use]
 extern crate log;
 
+pub mod admin;
+pub mod admission;
+pub mod audit;
+pub mod auth;
+pub mod calibration;
+pub mod clock;
 pub mod commands;
 pub mod config;
+pub mod delegation;
+pub mod health;
+pub mod http;
+pub mod jobs;
+pub mod metrics;
 pub mod payment;
 pub mod peer;
+pub mod policy;
 pub mod pow;
+pub mod profile;
+pub mod queue;
+pub mod registry;
+pub mod relay;
+pub mod signer;
+pub mod state;
 pub mod websocket;
 
 use nostr_rs_relay::event::Event;
 use std::sync::atomic::AtomicUsize;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-pub const CREATED_AT_DELTA_SEC: u64 = 600;
-
 pub static NEXT_USERID: AtomicUsize = AtomicUsize::new(1);
 
 pub fn get_timestamp() -> u64 {
@@ -25,6 +41,7 @@ pub fn get_timestamp() -> u64 {
 fn get_event_first_tag_with_value(event: &Event, tag: &str) -> Option<String> {
     event.tags
         .iter()
-        .find(|t| t.get(0).map_or(false, |c| c.to_lowercase() == tag.to_lowercase()))
-        .map(|t| t[1].to_string())
+        .find(|t| t.first().is_some_and(|c| c.to_lowercase() == tag.to_lowercase()))
+        .and_then(|t| t.get(1))
+        .map(|v| v.to_string())
 }
@@ -0,0 +1,152 @@
+use crate::jobs::JobStatus;
+use crate::state::AppState;
+use prometheus::{
+    exponential_buckets, Encoder, Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Prometheus metrics for the whole service, served on `/metrics`
+pub struct Metrics {
+    registry: Registry,
+    pub connections: IntGauge,
+    pub authenticated_peers: IntGauge,
+    /// Pubkeys authenticated across all connections (a connection can AUTH as several)
+    pub authenticated_pubkeys: IntGauge,
+    /// Queued and running jobs, refreshed from the job registry on each scrape
+    pub jobs_active: IntGaugeVec,
+    /// Jobs by final status (completed, failed or cancelled)
+    pub jobs_finished: IntCounterVec,
+    pub mining_duration: HistogramVec,
+    pub hashes: IntCounter,
+    /// Combined hashrate of running miners, refreshed on each scrape
+    pub hashrate: Gauge,
+    pub sats_debited: IntCounter,
+    pub sats_refunded: IntCounter,
+    pub rate_limited: IntCounter,
+    pub auth_failures: IntCounterVec,
+}
+
+/// The global metrics, registered on first use
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+// Difficulty label values, to keep label cardinality small
+pub fn difficulty_bucket(difficulty: u16) -> &'static str {
+    match difficulty {
+        0..=15 => "0-15",
+        16..=19 => "16-19",
+        20..=23 => "20-23",
+        24..=27 => "24-27",
+        28..=31 => "28-31",
+        _ => "32+",
+    }
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("pow".to_string()), None)
+            .expect("valid metrics registry");
+
+        let connections = IntGauge::new("websocket_connections", "Open websocket connections")
+            .expect("valid metric");
+        let authenticated_peers = IntGauge::new("authenticated_peers", "Websocket connections that completed AUTH")
+            .expect("valid metric");
+        let authenticated_pubkeys = IntGauge::new("authenticated_pubkeys", "Pubkeys authenticated on open websocket connections")
+            .expect("valid metric");
+        let jobs_active = IntGaugeVec::new(
+            Opts::new("jobs_active", "Jobs waiting for or using a miner"),
+            &["status", "difficulty"],
+        ).expect("valid metric");
+        let jobs_finished = IntCounterVec::new(
+            Opts::new("jobs_finished_total", "Finished jobs"),
+            &["status", "difficulty"],
+        ).expect("valid metric");
+        let mining_duration = HistogramVec::new(
+            HistogramOpts::new("mining_duration_seconds", "Time spent mining completed jobs")
+                .buckets(exponential_buckets(0.01, 4.0, 10).expect("valid buckets")),
+            &["difficulty"],
+        ).expect("valid metric");
+        let hashes = IntCounter::new("hashes_total", "Event ids hashed while mining")
+            .expect("valid metric");
+        let hashrate = Gauge::new("hashrate", "Combined hashes per second of running miners")
+            .expect("valid metric");
+        let sats_debited = IntCounter::new("sats_debited_total", "Satoshis charged for jobs")
+            .expect("valid metric");
+        let sats_refunded = IntCounter::new("sats_refunded_total", "Satoshis refunded for failed, cancelled or partly mined jobs")
+            .expect("valid metric");
+        let rate_limited = IntCounter::new("rate_limited_total", "Requests rejected by profile quotas")
+            .expect("valid metric");
+        let auth_failures = IntCounterVec::new(
+            Opts::new("auth_failures_total", "Failed NIP-42 and NIP-98 authentications"),
+            &["transport"],
+        ).expect("valid metric");
+
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(connections.clone()),
+            Box::new(authenticated_peers.clone()),
+            Box::new(authenticated_pubkeys.clone()),
+            Box::new(jobs_active.clone()),
+            Box::new(jobs_finished.clone()),
+            Box::new(mining_duration.clone()),
+            Box::new(hashes.clone()),
+            Box::new(hashrate.clone()),
+            Box::new(sats_debited.clone()),
+            Box::new(sats_refunded.clone()),
+            Box::new(rate_limited.clone()),
+            Box::new(auth_failures.clone()),
+        ];
+        for collector in collectors {
+            registry.register(collector).expect("metric registered once");
+        }
+
+        Self {
+            registry,
+            connections,
+            authenticated_peers,
+            authenticated_pubkeys,
+            jobs_active,
+            jobs_finished,
+            mining_duration,
+            hashes,
+            hashrate,
+            sats_debited,
+            sats_refunded,
+            rate_limited,
+            auth_failures,
+        }
+    }
+
+    pub fn job_finished(&self, status: &JobStatus, difficulty: u16) {
+        if status.is_finished() {
+            self.jobs_finished.with_label_values(&[status.name(), difficulty_bucket(difficulty)]).inc();
+        }
+    }
+
+    /// Prometheus text format, with the job gauges refreshed from current state
+    pub fn render(&self, state: &AppState) -> String {
+        self.jobs_active.reset();
+        let mut hashrate = 0.0;
+
+        for job in state.jobs.list() {
+            let status = job.status();
+            match status {
+                JobStatus::Queued => {},
+                JobStatus::Running => hashrate += job.control.progress(job.target_pow).hashrate,
+                _ => continue,
+            }
+            self.jobs_active.with_label_values(&[status.name(), difficulty_bucket(job.target_pow)]).inc();
+        }
+        self.hashrate.set(hashrate);
+
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Encoding metrics failed: {e:?}");
+        }
+
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
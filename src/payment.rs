@@ -1,6 +1,9 @@
 use anyhow::{anyhow, Result};
+use crate::config::Pricing;
+use crate::metrics::metrics;
+use crate::profile::Profile;
 
-fn price_for_difficulty(target_difficulty: u16) -> u32 {
+pub fn price_for_difficulty(pricing: &Pricing, profile: &Profile, target_difficulty: u16) -> u32 {
 
     // Each leading bit in difficulty effectively doubles the computational cost
     // This pricing model, while not doubling, seems like a fair starting point
@@ -28,17 +31,19 @@ fn price_for_difficulty(target_difficulty: u16) -> u32 {
     // 29  7817
     // 30  12167
 
-    // =(TARGET-7)^(TARGET/10)
-    ((target_difficulty-7) as f32).powf(target_difficulty as f32 /10.0) as u32
+    // =(TARGET-7)^(TARGET/10) with the default offset and divisor, then the profile multiplier
+    let base_price = (target_difficulty as f32 - pricing.difficulty_offset).powf(target_difficulty as f32 / pricing.exponent_divisor);
+    (base_price * profile.price_multiplier) as u32
 }
 
-pub fn payment_required(whitelist: &Vec<String>, pubkey: String) -> bool {
-    !whitelist.contains(&pubkey)
+pub fn payment_required(profile: &Profile) -> bool {
+    !profile.is_free()
 }
 
-pub async fn debt_account(pubkey: &str, difficulty: u16, source_event_id: &str) -> Result<()> {
+/// Charge pubkey for a job, returning the price in sats
+pub async fn debt_account(pricing: &Pricing, profile: &Profile, pubkey: &str, difficulty: u16, source_event_id: &str) -> Result<u32> {
     // Verify that pubkey has funds (and deduct?)
-    let price_sat = price_for_difficulty(difficulty);
+    let price_sat = price_for_difficulty(pricing, profile, difficulty);
     info!("Request cost {} satoshi for {difficulty} target difficulty", price_sat);
 
     // Connect to accounts and check account balance and deduct amount
@@ -46,24 +51,46 @@ pub async fn debt_account(pubkey: &str, difficulty: u16, source_event_id: &str)
     let payment_response: Result<()> = Ok(());
 
     if let Err(_e) = payment_response { //payment_response(&event.pubkey, price_sat) {
-
-        // TODO: Need to add logging / record keeping here for financial and troubleshooting
         return Err(anyhow!("{}: insuffecient funds", source_event_id))
     }
 
-    // TODO: Need to add logging / record keeping here for financial and troubleshooting
+    metrics().sats_debited.inc_by(price_sat as u64);
 
-    Ok(())
+    Ok(price_sat)
 }
 
-pub async fn credit_account(pubkey: &str, difficulty: u16, source_event_id: &str) -> Result<()> {
-    info!("Credit account request for: {pubkey}: difficulty {difficulty} for {source_event_id}");
+/// Refund pubkey for a job (all of it, or the difference when it mined less than it paid for)
+pub async fn credit_account(pubkey: &str, amount_sat: u32, source_event_id: &str) -> Result<()> {
+    info!("Credit account request for: {pubkey}: {amount_sat} satoshi for {source_event_id}");
+
+    async {}.await;
 
-    let _price_sat = price_for_difficulty(difficulty);
+    metrics().sats_refunded.inc_by(amount_sat as u64);
+
+    Ok(())
+}
 
+/// Check the accounts ledger can be reached
+pub async fn check_ledger() -> Result<()> {
+    // TODO: There is no ledger database yet (debt_account and credit_account are stubs)
     async {}.await;
 
-    // TODO: Need to add logging / record keeping here for financial and troubleshooting
+    Ok(())
+}
+
+/// Check the payment provider answers
+pub async fn check_payment_provider() -> Result<()> {
+    // TODO: There is no payment provider yet (debt_account and credit_account are stubs)
+    async {}.await;
 
     Ok(())
 }
+
+/// Admin balance adjustment. Positive credits the account, negative debits it.
+/// Fails until an accounts ledger is connected, so no adjustment is reported as done
+pub async fn adjust_balance(pubkey: &str, amount_sat: i64) -> Result<()> {
+    info!("Adjusting {pubkey}'s account by {amount_sat} satoshi");
+
+    // Connect to accounts and adjust the balance
+    Err(anyhow!("unsupported: no accounts ledger for balance adjustments"))
+}
@@ -1,18 +1,25 @@
 use anyhow::{anyhow,Result};
-use crate::{get_timestamp, get_event_first_tag_with_value};
+use crate::auth::check_created_at_window;
+use crate::config::AppConfig;
+use crate::delegation::GrantStore;
+use crate::{get_event_first_tag_with_value, get_timestamp};
 use nostr_rs_relay::event::Event;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
 use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::{Notify, RwLock};
 use uuid::Uuid;
 
-const AUTH_CREATED_AT_DELTA_SEC: u64 = 300; // 5 minutes
-
 #[derive(Debug, Clone)]
 pub struct PeerInfo {
     pub id: usize,
     pub real_ip: Option<IpAddr>,
     pub auth_challenge: String,
-    pub auth_confirmed: bool,
-    pub pubkey: Option<String>,
+    /// Every pubkey proven with an AUTH event for this connection's challenge
+    pub pubkeys: BTreeSet<String>,
+    /// The job being mined for this connection (cancelled if it closes)
+    pub job_id: Option<String>,
 }
 
 impl PeerInfo {
@@ -24,16 +31,20 @@ impl PeerInfo {
           id,
           real_ip,
           auth_challenge,
-          auth_confirmed: false,
-          pubkey: None
+          pubkeys: BTreeSet::new(),
+          job_id: None,
         }
     }
 
+    pub fn is_authenticated(&self) -> bool {
+        !self.pubkeys.is_empty()
+    }
+
     pub fn generate_auth_request_cmd(&self) -> String {
         format!(r#"["AUTH", "{}"]"#, self.auth_challenge)
     }
 
-    pub fn check_auth_response(&mut self, relay_identifier: String, event: &Event) -> Result<()> {
+    pub fn check_auth_response(&mut self, app_config: &AppConfig, event: &Event) -> Result<()> {
 
         // Ensure event is valid
         event.validate()?;
@@ -43,14 +54,11 @@ impl PeerInfo {
             return Err(anyhow!("Invalid event kind"));
         }
 
-        // Ensure event created_at is reasonable (within 5 minutes)
-        let now = get_timestamp();
-        if !(now-AUTH_CREATED_AT_DELTA_SEC..=now+AUTH_CREATED_AT_DELTA_SEC).contains(&event.created_at) {
-            return Err(anyhow!("Invalid event created_at. Must be within 5 minutes now"));
-        }
+        // Ensure event created_at is reasonable (within 5 minutes by default)
+        check_created_at_window(app_config.clock.as_ref(), event.created_at, app_config.auth_created_at_delta_sec)?;
 
         // Ensure relay tag matches
-        if Some(relay_identifier) != get_event_first_tag_with_value(&event, "relay") {
+        if Some(&app_config.relay_identifier) != get_event_first_tag_with_value(&event, "relay").as_ref() {
             return Err(anyhow!("Invalid relay tag"));
         }
 
@@ -59,12 +67,152 @@ impl PeerInfo {
             return Err(anyhow!("Invalid challenge tag"));
         }
 
+        // Ensure this connection can take another pubkey
+        if !self.pubkeys.contains(&event.pubkey) && self.pubkeys.len() >= app_config.websocket.max_pubkeys {
+            return Err(anyhow!("Too many pubkeys for this connection"));
+        }
+
         // Otherwise, we are all good!
-        self.auth_confirmed = true;
-        self.pubkey = Some(event.pubkey.to_string());
+        self.pubkeys.insert(event.pubkey.to_string());
 
-        debug!("AUTHENTICATED: {:?}", self.pubkey);
+        debug!("AUTHENTICATED: {} ({} pubkeys)", &event.pubkey, self.pubkeys.len());
 
         Ok(())
     }
+
+    /// The authenticated pubkey a PoW request for `event` is made (and billed) as: the
+    /// event's own pubkey, else its NIP-26 delegator, else a pubkey that granted it.
+    /// Otherwise any authenticated pubkey, which is then rejected as a mismatch
+    pub fn requesting_pubkey(&self, event: &Event, grants: &GrantStore) -> Option<String> {
+        if self.pubkeys.contains(&event.pubkey) {
+            return Some(event.pubkey.clone())
+        }
+
+        // Checked with the rest of the request, so only pick the pubkey here
+        let delegator = event.tags.iter()
+            .find(|tag| tag.first().map(String::as_str) == Some("delegation"))
+            .and_then(|tag| tag.get(1))
+            .map(|delegator| delegator.to_lowercase());
+
+        delegator.filter(|delegator| self.pubkeys.contains(delegator))
+            .or_else(|| self.pubkeys.iter().find(|pubkey| grants.is_granted(pubkey, &event.pubkey)).cloned())
+            .or_else(|| self.pubkeys.first().cloned())
+    }
+}
+
+/// A connected websocket peer
+struct ConnectedPeer {
+    info: Arc<RwLock<PeerInfo>>,
+    connected_at: u64,
+    kick: Arc<Notify>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PeerSummary {
+    pub peer_id: usize,
+    pub real_ip: Option<IpAddr>,
+    pub pubkeys: Vec<String>,
+    pub connected_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct Bans {
+    pub pubkeys: BTreeSet<String>,
+    pub ips: BTreeSet<IpAddr>,
+}
+
+/// Open websocket connections and banned pubkeys and IPs, for the admin API.
+/// Bans are saved with the admin state (see `admin::AdminStore`).
+#[derive(Default)]
+pub struct PeerRegistry {
+    peers: RwLock<HashMap<usize, ConnectedPeer>>,
+    bans: RwLock<Bans>,
+}
+
+impl PeerRegistry {
+
+    /// Track a new connection. The returned Notify fires when the peer is kicked
+    pub async fn register(&self, info: Arc<RwLock<PeerInfo>>) -> Arc<Notify> {
+        let peer_id = info.read().await.id;
+        let kick = Arc::new(Notify::new());
+
+        self.peers.write().await.insert(peer_id, ConnectedPeer {
+            info,
+            connected_at: get_timestamp(),
+            kick: Arc::clone(&kick),
+        });
+
+        kick
+    }
+
+    pub async fn remove(&self, peer_id: usize) {
+        self.peers.write().await.remove(&peer_id);
+    }
+
+    pub async fn list(&self) -> Vec<PeerSummary> {
+        let mut summaries = Vec::new();
+
+        for (peer_id, peer) in self.peers.read().await.iter() {
+            let info = peer.info.read().await;
+            summaries.push(PeerSummary {
+                peer_id: *peer_id,
+                real_ip: info.real_ip,
+                pubkeys: info.pubkeys.iter().cloned().collect(),
+                connected_at: peer.connected_at,
+            });
+        }
+
+        summaries.sort_by_key(|summary| summary.peer_id);
+        summaries
+    }
+
+    /// Disconnect a peer. Returns false if it isn't connected
+    pub async fn kick(&self, peer_id: usize) -> bool {
+        match self.peers.read().await.get(&peer_id) {
+            None => false,
+            Some(peer) => {
+                peer.kick.notify_one();
+                true
+            },
+        }
+    }
+
+    /// Ban a pubkey and/or IP, disconnecting any matching peers
+    pub async fn ban(&self, pubkey: Option<&str>, ip: Option<IpAddr>) {
+        {
+            let mut bans = self.bans.write().await;
+            bans.pubkeys.extend(pubkey.map(str::to_string));
+            bans.ips.extend(ip);
+        }
+
+        // A copy, so the bans lock isn't held while waiting on each peer's info
+        let bans = self.bans().await;
+
+        for peer in self.peers.read().await.values() {
+            let info = peer.info.read().await;
+            let banned_pubkey = info.pubkeys.iter().any(|pubkey| bans.pubkeys.contains(pubkey));
+            if banned_pubkey || info.real_ip.is_some_and(|ip| bans.ips.contains(&ip)) {
+                peer.kick.notify_one();
+            }
+        }
+    }
+
+    pub async fn unban(&self, pubkey: Option<&str>, ip: Option<IpAddr>) {
+        let mut bans = self.bans.write().await;
+        if let Some(pubkey) = pubkey {
+            bans.pubkeys.remove(pubkey);
+        }
+        if let Some(ip) = ip {
+            bans.ips.remove(&ip);
+        }
+    }
+
+    pub async fn bans(&self) -> Bans {
+        self.bans.read().await.clone()
+    }
+
+    pub async fn is_banned(&self, pubkey: Option<&str>, ip: Option<IpAddr>) -> bool {
+        let bans = self.bans.read().await;
+        pubkey.is_some_and(|pubkey| bans.pubkeys.contains(pubkey)) || ip.is_some_and(|ip| bans.ips.contains(&ip))
+    }
 }
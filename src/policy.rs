@@ -0,0 +1,218 @@
+use anyhow::{anyhow, Result};
+use crate::config::PolicySettings;
+use crate::get_timestamp;
+use nostr_rs_relay::event::Event;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::IpAddr;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration};
+
+const PLUGIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Operator rules an event must pass before we spend any time mining it
+pub struct EventPolicy {
+    pub allowed_kinds: Vec<u64>,
+    pub denied_kinds: Vec<u64>,
+    pub max_content_length: Option<usize>,
+    pub max_tags: Option<usize>,
+    pub required_tags: Vec<String>,
+    pub content_deny_patterns: Vec<Regex>,
+    pub plugin: Option<PolicyPlugin>,
+}
+
+impl TryFrom<&PolicySettings> for EventPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(settings: &PolicySettings) -> Result<Self> {
+        let content_deny_patterns = settings.content_deny_regex
+            .iter()
+            .map(|pattern| Regex::new(pattern).map_err(|e| anyhow!("Invalid content deny regex {pattern:?}: {e}")))
+            .collect::<Result<Vec<Regex>>>()?;
+
+        Ok(Self {
+            allowed_kinds: settings.allowed_kinds.clone(),
+            denied_kinds: settings.denied_kinds.clone(),
+            max_content_length: settings.max_content_length,
+            max_tags: settings.max_tags,
+            required_tags: settings.required_tags.clone(),
+            content_deny_patterns,
+            plugin: settings.plugin.clone().map(PolicyPlugin::new),
+        })
+    }
+}
+
+impl EventPolicy {
+
+    /// Run the static rules, then the external policy plugin (if configured)
+    pub async fn admit(&self, event: &Event, pubkey: &str, real_ip: Option<IpAddr>, target_difficulty: u16) -> Result<()> {
+        self.check(event)?;
+
+        if let Some(plugin) = &self.plugin {
+            plugin.admit(event, pubkey, real_ip, target_difficulty).await?;
+        }
+
+        Ok(())
+    }
+
+    pub fn check(&self, event: &Event) -> Result<()> {
+
+        if !self.allowed_kinds.is_empty() && !self.allowed_kinds.contains(&event.kind) {
+            return Err(anyhow!("blocked: event kind {} is not allowed", event.kind))
+        }
+
+        if self.denied_kinds.contains(&event.kind) {
+            return Err(anyhow!("blocked: event kind {} is not allowed", event.kind))
+        }
+
+        if let Some(max_content_length) = self.max_content_length {
+            if event.content.len() > max_content_length {
+                return Err(anyhow!("blocked: content exceeds {max_content_length} bytes"))
+            }
+        }
+
+        if let Some(max_tags) = self.max_tags {
+            if event.tags.len() > max_tags {
+                return Err(anyhow!("blocked: event exceeds {max_tags} tags"))
+            }
+        }
+
+        for required_tag in &self.required_tags {
+            if !event.tags.iter().any(|t| t.first() == Some(required_tag)) {
+                return Err(anyhow!("blocked: missing required tag {required_tag}"))
+            }
+        }
+
+        if self.content_deny_patterns.iter().any(|pattern| pattern.is_match(&event.content)) {
+            return Err(anyhow!("blocked: content not allowed"))
+        }
+
+        Ok(())
+    }
+}
+
+/// strfry style write policy plugin. A long running program that is sent one
+/// JSON request per line on stdin, and replies with one JSON line on stdout.
+///
+/// Request:  {"type":"new","event":{...},"receivedAt":<ts>,"sourceType":"IP4","sourceInfo":"<ip>","authed":"<pubkey>","difficulty":<n>}
+/// Response: {"id":"<event id>","action":"accept|reject|shadowReject","msg":"<reason>"}
+pub struct PolicyPlugin {
+    path: String,
+    process: Mutex<Option<PluginProcess>>,
+}
+
+struct PluginProcess {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PluginResponse {
+    id: String,
+    action: String,
+    #[serde(default)]
+    msg: String,
+}
+
+impl PolicyPlugin {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            process: Mutex::new(None),
+        }
+    }
+
+    async fn admit(&self, event: &Event, pubkey: &str, real_ip: Option<IpAddr>, target_difficulty: u16) -> Result<()> {
+
+        let source_type = match real_ip {
+            Some(IpAddr::V4(_)) => "IP4",
+            Some(IpAddr::V6(_)) => "IP6",
+            None => "Unknown",
+        };
+
+        let request = json!({
+            "type": "new",
+            "event": event,
+            "receivedAt": get_timestamp(),
+            "sourceType": source_type,
+            "sourceInfo": real_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            "authed": pubkey,
+            "difficulty": target_difficulty,
+        });
+
+        let response = self.exchange(request.to_string()).await.map_err(|e| {
+            error!("Policy plugin {} failed: {e:?}", self.path);
+            anyhow!("error: policy check unavailable")
+        })?;
+
+        if response.id != event.id {
+            error!("Policy plugin {} replied for {} while checking {}", self.path, response.id, event.id);
+            return Err(anyhow!("error: policy check unavailable"))
+        }
+
+        match response.action.as_str() {
+            "accept" => Ok(()),
+            "reject" if !response.msg.is_empty() => Err(anyhow!("blocked: {}", response.msg)),
+            _ => Err(anyhow!("blocked: rejected by policy")),
+        }
+    }
+
+    // Send one request line and wait for its reply, restarting the plugin if it died or stalled
+    async fn exchange(&self, request: String) -> Result<PluginResponse> {
+        let mut process = self.process.lock().await;
+
+        let plugin_process = match process.as_mut() {
+            Some(plugin_process) => plugin_process,
+            None => process.insert(self.spawn()?),
+        };
+
+        let response = match timeout(PLUGIN_TIMEOUT, plugin_process.exchange(request)).await {
+            Ok(response) => response,
+            Err(_) => Err(anyhow!("timed out after {PLUGIN_TIMEOUT:?}")),
+        };
+
+        if response.is_err() {
+            // Dropping the process kills it, and we start a fresh one next request
+            *process = None;
+        }
+
+        response
+    }
+
+    fn spawn(&self) -> Result<PluginProcess> {
+        info!("Starting policy plugin: {}", self.path);
+
+        let mut child = Command::new(&self.path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdin = child.stdin.take().ok_or_else(|| anyhow!("policy plugin stdin unavailable"))?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("policy plugin stdout unavailable"))?;
+
+        Ok(PluginProcess {
+            _child: child,
+            stdin,
+            stdout: BufReader::new(stdout).lines(),
+        })
+    }
+}
+
+impl PluginProcess {
+    async fn exchange(&mut self, mut request: String) -> Result<PluginResponse> {
+        request.push('\n');
+        self.stdin.write_all(request.as_bytes()).await?;
+        self.stdin.flush().await?;
+
+        match self.stdout.next_line().await? {
+            Some(line) => Ok(serde_json::from_str(&line)?),
+            None => Err(anyhow!("policy plugin exited")),
+        }
+    }
+}
@@ -1,13 +1,201 @@
 use anyhow::{anyhow,Result};
-use crate::CREATED_AT_DELTA_SEC;
-use crate::get_timestamp;
+use crate::auth::check_created_at_window;
+use crate::clock::{Clock, SystemClock};
+use crate::config::AppConfig;
+use crate::delegation::{delegator, GrantStore};
+use crate::metrics::metrics;
+use crate::profile::Profile;
 use nostr_rs_relay::event::Event;
 use nostr_rust::events::EventPrepare;
-use rand::Rng;
+use rand::rngs::{StdRng, ThreadRng};
+use rand::{Rng, SeedableRng};
 use serde_json::json;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use tokio::sync::Notify;
 use tokio::task;
 use tokio::time::Instant;
 
+// How often (in attempts) the miner reports progress and checks for cancellation
+const PROGRESS_INTERVAL: u64 = 4096;
+
+/// Shared between a job and its miner thread, to follow progress and cancel mining
+#[derive(Default, Debug)]
+pub struct MiningControl {
+    cancelled: AtomicBool,
+    timed_out: AtomicBool,
+    cancel_notify: Notify,
+    attempts: AtomicU64,
+    best_zeros: AtomicU16,
+    best: Mutex<Option<BestNonce>>,
+    started_at: OnceLock<Instant>,
+}
+
+/// Where the miner puts the nonce tag. Any nonce tags already on the event are replaced
+#[derive(Serialize, Deserialize, clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NonceTagPosition {
+    First,
+    #[default]
+    Last,
+}
+
+/// How the miner picks each nonce. Sequential and seeded mining (with a fixed clock)
+/// always produce the same event, for tests and comparable benchmarks
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(tag = "strategy", rename_all = "lowercase")]
+pub enum NonceStrategy {
+    #[default]
+    Random,
+    /// offset, offset + 1, ...
+    Sequential { offset: u64 },
+    /// Random from a seeded RNG (reproducible for a given build)
+    Seeded { seed: u64 },
+}
+
+/// How the miner builds each attempt
+#[derive(Debug, Clone)]
+pub struct MiningOptions {
+    pub nonce_tag_position: NonceTagPosition,
+    /// Keep the client's created_at instead of bumping it to now on every attempt,
+    /// with a u64 nonce so the nonce space doesn't run out
+    pub preserve_created_at: bool,
+    pub nonce_strategy: NonceStrategy,
+    /// Time created_at is bumped to
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for MiningOptions {
+    fn default() -> Self {
+        Self {
+            nonce_tag_position: NonceTagPosition::default(),
+            preserve_created_at: false,
+            nonce_strategy: NonceStrategy::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+// Nonce source for a single mining run
+enum Nonces {
+    Random(ThreadRng),
+    Seeded(Box<StdRng>),
+    Sequential(u64),
+}
+
+impl Nonces {
+    fn new(strategy: NonceStrategy) -> Self {
+        match strategy {
+            NonceStrategy::Random => Nonces::Random(rand::thread_rng()),
+            NonceStrategy::Seeded { seed } => Nonces::Seeded(Box::new(StdRng::seed_from_u64(seed))),
+            NonceStrategy::Sequential { offset } => Nonces::Sequential(offset),
+        }
+    }
+
+    // Random nonces are u32, unless the nonce space needs widening (u64)
+    fn next(&mut self, wide: bool) -> u64 {
+        match self {
+            Nonces::Random(rng) => random_nonce(rng, wide),
+            Nonces::Seeded(rng) => random_nonce(rng.as_mut(), wide),
+            Nonces::Sequential(next) => {
+                let nonce = *next;
+                *next = next.wrapping_add(1);
+                nonce
+            },
+        }
+    }
+}
+
+fn random_nonce(rng: &mut impl Rng, wide: bool) -> u64 {
+    match wide {
+        true => rng.gen::<u64>(),
+        false => rng.gen_range(0..u32::MAX) as u64,
+    }
+}
+
+/// The nonce with the most leading zero bits found so far
+#[derive(Debug, Clone)]
+pub struct BestNonce {
+    pub id: String,
+    pub created_at: u64,
+    /// Event tags, including the nonce tag
+    pub tags: Vec<Vec<String>>,
+    pub leading_zeros: u16,
+}
+
+/// Snapshot of a running miner
+#[derive(Serialize, Debug, Clone)]
+pub struct MiningProgress {
+    pub attempts: u64,
+    pub best_zeros: u16,
+    pub elapsed_ms: u128,
+    pub hashrate: f64,
+    /// Estimated seconds left, from the expected 2^difficulty attempts at the current hashrate
+    pub eta_sec: Option<f64>,
+}
+
+impl MiningControl {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.cancel_notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Stop mining because the job ran over its time budget
+    pub fn time_out(&self) {
+        self.timed_out.store(true, Ordering::Relaxed);
+        self.cancel();
+    }
+
+    pub fn is_timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+
+    pub fn best(&self) -> Option<BestNonce> {
+        self.best.lock().expect("mining control lock poisoned").clone()
+    }
+
+    /// Resolves once the job is cancelled
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.cancel_notify.notified();
+            if self.is_cancelled() {
+                return
+            }
+            notified.await;
+        }
+    }
+
+    pub fn attempts(&self) -> u64 {
+        self.attempts.load(Ordering::Relaxed)
+    }
+
+    pub fn progress(&self, target_difficulty: u16) -> MiningProgress {
+        let attempts = self.attempts();
+        let elapsed = self.started_at.get().map(|started_at| started_at.elapsed()).unwrap_or_default();
+
+        let hashrate = match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => attempts as f64 / secs,
+            _ => 0.0,
+        };
+
+        let expected_attempts = 2f64.powi(target_difficulty as i32);
+        let eta_sec = (hashrate > 0.0).then(|| (expected_attempts - attempts as f64).max(0.0) / hashrate);
+
+        MiningProgress {
+            attempts,
+            best_zeros: self.best_zeros.load(Ordering::Relaxed),
+            elapsed_ms: elapsed.as_millis(),
+            hashrate,
+            eta_sec,
+        }
+    }
+}
+
 pub fn get_digest_input(event: &EventPrepare) -> String {
     json!([
         0,
@@ -37,7 +225,7 @@ pub fn count_leading_zero_bits(content_id: Vec<u8>) -> u16 {
     total
 }
 
-pub async fn generate_pow(target_difficulty: u16, mut event: Event) -> Result<Event> {
+pub async fn generate_pow(target_difficulty: u16, mut event: Event, control: Arc<MiningControl>, options: MiningOptions) -> Result<Event> {
 
     // Generate event payload
     let event_prepare = EventPrepare {
@@ -50,7 +238,7 @@ pub async fn generate_pow(target_difficulty: u16, mut event: Event) -> Result<Ev
 
     // Use spawn_blocking to offload to a new thread
     let compute = task::spawn_blocking(move || {
-        generate_pow_event(event_prepare, target_difficulty).unwrap()
+        generate_pow_event(event_prepare, target_difficulty, &control, &options)
     });
 
     match compute.await {
@@ -59,11 +247,15 @@ pub async fn generate_pow(target_difficulty: u16, mut event: Event) -> Result<Ev
             return Err(anyhow!("Event Proof of Work failed"))
         },
 
-        Ok((event_id, nonce, leading_zeros)) => {
+        Ok(Err(e)) => return Err(e),
+
+        Ok(Ok((event_id, mined_event, leading_zeros))) => {
 
             // TODO: Refactor this, but we need to make Event and EventPrepare work or make our own
+            // The miner may bump created_at, and the id covers it, so copy both back
             event.id = event_id;
-            event.tags.push(nonce);
+            event.created_at = mined_event.created_at;
+            event.tags = mined_event.tags;
 
             info!("Target Difficulty: {target_difficulty}. Found: {leading_zeros}");
 
@@ -76,71 +268,212 @@ pub async fn generate_pow(target_difficulty: u16, mut event: Event) -> Result<Ev
     Ok(event)
 }
 
-pub fn generate_pow_event(mut event: EventPrepare, difficulty: u16) -> Result<(String, Vec<String>, u16)> {
-    let mut rng = rand::thread_rng();
+/// The event as mined with a nonce the miner found (the miner sets the nonce tag, and bumps created_at unless preserved)
+pub fn event_with_nonce(mut event: Event, best: BestNonce) -> Event {
+    event.id = best.id;
+    event.created_at = best.created_at;
+    event.tags = best.tags;
+    event.sig = "".to_owned();
+    event
+}
+
+/// Remove any nonce tags (e.g. from a client's own mining attempt), keeping the other
+/// tags in order, and add a nonce tag at `position`. Returns the nonce tag's index
+pub fn set_nonce_tag(tags: &mut Vec<Vec<String>>, nonce_tag: Vec<String>, position: NonceTagPosition) -> usize {
+    tags.retain(|tag| tag.first().map(String::as_str) != Some("nonce"));
+
+    let index = match position {
+        NonceTagPosition::First => 0,
+        NonceTagPosition::Last => tags.len(),
+    };
+    tags.insert(index, nonce_tag);
+
+    index
+}
+
+/// Mine until the event id has `difficulty` leading zero bits. Returns the id, and the
+/// event as mined (with the nonce tag set, and created_at bumped unless preserved)
+pub fn generate_pow_event(mut event: EventPrepare, difficulty: u16, control: &MiningControl, options: &MiningOptions) -> Result<(String, EventPrepare, u16)> {
+    let mut nonces = Nonces::new(options.nonce_strategy);
 
-    let start = Instant::now();
-    let mut attempts = 0;
+    // Only the nonce changes between attempts, so the tag is set once and updated in place
+    let nonce_tag = vec!["nonce".to_string(), "0".to_string(), difficulty.to_string()];
+    let nonce_index = set_nonce_tag(&mut event.tags, nonce_tag, options.nonce_tag_position);
+
+    let start = *control.started_at.get_or_init(Instant::now);
+    let mut attempts: u64 = 0;
+    let mut best_zeros: u16 = 0;
     loop {
 
-        // We need to set created_at on first loop for temporal spam-protection
-        // Note: If we don't bump timestamp, we may run out of nonce options
-        event.created_at = get_timestamp();
+        if attempts % PROGRESS_INTERVAL == 0 {
+            control.attempts.store(attempts, Ordering::Relaxed);
+            if attempts > 0 {
+                metrics().hashes.inc_by(PROGRESS_INTERVAL);
+            }
 
-        let nonce: u32 = rng.gen_range(0..u32::MAX);
+            if control.is_cancelled() {
+                info!("POW {difficulty} Request - cancelled after {attempts} attempts");
+                return Err(anyhow!("cancelled"))
+            }
+        }
 
-        let nonce_tag = vec![
-            "nonce".to_string(),
-            nonce.to_string(),
-            difficulty.to_string(),
-        ];
+        // We need to set created_at on first loop for temporal spam-protection
+        // Note: If we don't bump timestamp, we may run out of nonce options
+        if !options.preserve_created_at {
+            event.created_at = options.clock.now();
+        }
 
-        event.tags.push(nonce_tag.clone());
+        event.tags[nonce_index][1] = nonces.next(options.preserve_created_at).to_string();
 
         let content_id = get_content_id(&event);
         let content_id_hex = hex::decode(&content_id)?;
 
         let leading_zeros = count_leading_zero_bits(content_id_hex);
+        if leading_zeros > best_zeros {
+            best_zeros = leading_zeros;
+            control.best_zeros.store(best_zeros, Ordering::Relaxed);
+
+            // Kept so a job that runs out of time can still return its best result
+            *control.best.lock().expect("mining control lock poisoned") = Some(BestNonce {
+                id: content_id.clone(),
+                created_at: event.created_at,
+                tags: event.tags.clone(),
+                leading_zeros,
+            });
+        }
+
         if leading_zeros >= difficulty {
 
+            control.attempts.store(attempts, Ordering::Relaxed);
+            metrics().hashes.inc_by(attempts % PROGRESS_INTERVAL + 1);
             let total_duration = Instant::now().duration_since(start);
 
             // POW 25 Request - found 25 with 19109387 attempts in 737417 ms (Macbook Pro)
             info!("POW {difficulty} Request - found {leading_zeros} with {attempts} attempts in {} ms", total_duration.as_millis());
-            return Ok((content_id, nonce_tag, leading_zeros))
+            return Ok((content_id, event, leading_zeros))
         }
 
-        // Remove failed nonce tag
-        event.tags.pop();
-
         attempts += 1;
     }
 }
 
-pub fn validate_pow_request(min_pow: u16, max_pow: u16, target_difficulty: u16, event: &Event, request_pubkey: &str) -> Result<()> {
+/// NIP-13 verification of a mined event
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    /// The event id is the hash of the event
+    pub id_matches: bool,
+    /// Leading zero bits of the event's hash
+    pub difficulty: u16,
+    /// Target difficulty committed to in the nonce tag's third element
+    pub committed_difficulty: Option<u16>,
+    pub nonce_tag: bool,
+}
+
+impl Verification {
+    /// Valid NIP-13 proof of work, committed to and mined at `min_difficulty` or more
+    pub fn is_valid(&self, min_difficulty: u16) -> bool {
+        self.id_matches
+            && self.difficulty >= min_difficulty
+            && self.committed_difficulty.is_some_and(|committed| committed >= min_difficulty)
+    }
+}
+
+/// Recompute the event id, count its leading zero bits and read the committed target from the nonce tag
+pub fn verify(event: &Event) -> Verification {
+
+    let event_prepare = EventPrepare {
+        pub_key: event.pubkey.clone(),
+        created_at: event.created_at,
+        kind: event.kind as u16,
+        tags: event.tags.clone(),
+        content: event.content.clone(),
+    };
+
+    let content_id = get_content_id(&event_prepare);
+
+    let nonce_tag = event.tags.iter().find(|tag| tag.first().is_some_and(|name| name == "nonce"));
+
+    Verification {
+        id_matches: content_id == event.id,
+        difficulty: count_leading_zero_bits(hex::decode(&content_id).unwrap_or_default()),
+        committed_difficulty: nonce_tag
+            .and_then(|tag| tag.get(2))
+            .and_then(|target| target.parse::<u16>().ok()),
+        nonce_tag: nonce_tag.is_some(),
+    }
+}
+
+/// NIP-13 check of a mined event: the id must match the event, have at least
+/// `min_difficulty` leading zero bits, and the nonce tag must commit to a target
+/// of at least `min_difficulty`. Returns the leading zero bits
+pub fn check_nip13(event: &Event, min_difficulty: u16) -> Result<u16> {
+
+    let verification = verify(event);
+
+    if !verification.id_matches {
+        return Err(anyhow!("event id doesn't match the event"))
+    }
+
+    let leading_zeros = verification.difficulty;
+    if leading_zeros < min_difficulty {
+        return Err(anyhow!("event id has {leading_zeros} leading zero bits, expected {min_difficulty}"))
+    }
+
+    let committed_target = verification.committed_difficulty
+        .ok_or_else(|| anyhow!("missing nonce tag target difficulty"))?;
+
+    if committed_target < min_difficulty {
+        return Err(anyhow!("nonce tag commits to {committed_target}, expected {min_difficulty}"))
+    }
+
+    Ok(leading_zeros)
+}
+
+pub fn validate_pow_request(app_config: &AppConfig, grants: &GrantStore, profile: &Profile, target_difficulty: u16, event: &Event, request_pubkey: &str) -> Result<()> {
 
     info!("{event:?}");
 
-    // Validate target_difficulty is between min and max
+    // Validate target_difficulty is between the profile min and max
+    let (min_pow, max_pow) = (profile.min_pow_difficulty, profile.max_pow_difficulty);
     if !(min_pow..=max_pow).contains(&target_difficulty) {
         return Err(anyhow!("restricted: target difficulty must be between {min_pow} and {max_pow}"))
     }
 
+    // Validate the profile allows this kind
+    if !profile.allowed_kinds.is_empty() && !profile.allowed_kinds.contains(&event.kind) {
+        return Err(anyhow!("restricted: event kind {} is not available for your profile", event.kind))
+    }
+
     // Validate signature (prevent impersonation and validate the pubkey)
     if let Err(_) = event.validate() {
         return Err(anyhow!("error: invalid input event"))
     }
 
-    // Check request event POW matches authorised pubkey
+    // Check request event POW matches authorised pubkey (or is delegated to them)
     if event.pubkey != request_pubkey {
+        check_delegated_request(app_config, grants, event, request_pubkey)?;
+    }
+
+    // Check event created_at is reasonable (within 10 minutes by default)
+    check_created_at_window(app_config.clock.as_ref(), event.created_at, app_config.created_at_delta_sec)?;
+
+    Ok(())
+}
+
+// Another pubkey's event can be mined (billed to the requester) when the requester
+// granted that pubkey, or delegated to it with NIP-26
+fn check_delegated_request(app_config: &AppConfig, grants: &GrantStore, event: &Event, request_pubkey: &str) -> Result<()> {
+    if !app_config.delegation.enabled {
         return Err(anyhow!("error: event pubkey doesn't match authenticated pubkey"))
     }
 
-    // Check event created_at is reasonable (within 15 minutes)
-    let now = get_timestamp();
-    if !(now-CREATED_AT_DELTA_SEC..=now+CREATED_AT_DELTA_SEC).contains(&event.created_at) {
-        return Err(anyhow!("Invalid event created_at. Must be within 5 minutes now"));
+    if grants.is_granted(request_pubkey, &event.pubkey) {
+        return Ok(())
     }
 
-    Ok(())
+    match delegator(event) {
+        Ok(Some(delegator)) if delegator == request_pubkey => Ok(()),
+        Ok(_) => Err(anyhow!("restricted: event pubkey isn't delegated to or granted by authenticated pubkey")),
+        Err(e) => Err(anyhow!("error: {e}")),
+    }
 }
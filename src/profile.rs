@@ -0,0 +1,306 @@
+use anyhow::{anyhow, Result};
+use crate::config::AppConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant};
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Profile for pubkeys in the legacy `pubkey_whitelist`
+pub const WHITELIST_PROFILE: &str = "whitelist";
+
+const NIP05_CACHE_TTL: Duration = Duration::from_secs(600);
+const NIP05_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+const JOB_QUOTA_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Profile as written in the config file. Unset difficulty bounds use the global bounds
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_pow_difficulty: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_pow_difficulty: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_jobs: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_jobs_per_hour: Option<usize>,
+    #[serde(default = "default_price_multiplier")]
+    pub price_multiplier: f32,
+    #[serde(default)]
+    pub priority: u8,
+    #[serde(default)]
+    pub allowed_kinds: Vec<u64>,
+    #[serde(default)]
+    pub pubkeys: Vec<String>,
+    #[serde(default)]
+    pub nip05_domains: Vec<String>,
+}
+
+fn default_price_multiplier() -> f32 {
+    1.0
+}
+
+impl Default for ProfileSettings {
+    fn default() -> Self {
+        Self {
+            min_pow_difficulty: None,
+            max_pow_difficulty: None,
+            max_concurrent_jobs: None,
+            max_jobs_per_hour: None,
+            price_multiplier: default_price_multiplier(),
+            priority: 0,
+            allowed_kinds: Vec::new(),
+            pubkeys: Vec::new(),
+            nip05_domains: Vec::new(),
+        }
+    }
+}
+
+/// What a pubkey is allowed to request, and what it pays
+#[derive(Serialize, Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub min_pow_difficulty: u16,
+    pub max_pow_difficulty: u16,
+    pub max_concurrent_jobs: Option<usize>,
+    pub max_jobs_per_hour: Option<usize>,
+    /// 0 means free (no payment required)
+    pub price_multiplier: f32,
+    /// Higher priority jobs are mined first when all miners are busy
+    pub priority: u8,
+    /// Empty allows all kinds (subject to the event policy)
+    pub allowed_kinds: Vec<u64>,
+    pub pubkeys: Vec<String>,
+    pub nip05_domains: Vec<String>,
+}
+
+impl Profile {
+    pub fn new(name: &str, settings: &ProfileSettings, min_pow_difficulty: u16, max_pow_difficulty: u16) -> Self {
+        Self {
+            name: name.to_string(),
+            min_pow_difficulty: settings.min_pow_difficulty.unwrap_or(min_pow_difficulty),
+            max_pow_difficulty: settings.max_pow_difficulty.unwrap_or(max_pow_difficulty),
+            max_concurrent_jobs: settings.max_concurrent_jobs,
+            max_jobs_per_hour: settings.max_jobs_per_hour,
+            price_multiplier: settings.price_multiplier,
+            priority: settings.priority,
+            allowed_kinds: settings.allowed_kinds.clone(),
+            pubkeys: settings.pubkeys.clone(),
+            nip05_domains: settings.nip05_domains.iter().map(|d| d.to_lowercase()).collect(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.min_pow_difficulty > self.max_pow_difficulty {
+            return Err(anyhow!("profile {}: min_pow_difficulty is greater than max_pow_difficulty", self.name))
+        }
+
+        if self.max_pow_difficulty > 256 {
+            return Err(anyhow!("profile {}: max_pow_difficulty must be 256 or less", self.name))
+        }
+
+        if !self.price_multiplier.is_finite() || self.price_multiplier < 0.0 {
+            return Err(anyhow!("profile {}: price_multiplier must be 0 or more", self.name))
+        }
+
+        Ok(())
+    }
+
+    pub fn is_free(&self) -> bool {
+        self.price_multiplier == 0.0
+    }
+}
+
+/// Assigns pubkeys to profiles, and tracks their job quotas.
+///
+/// A pubkey gets the first match of: a runtime assignment (admin API), a profile
+/// listing the pubkey, a profile with a NIP-05 domain that lists the pubkey, or
+/// else the default profile. Where several profiles match, the highest priority wins.
+///
+/// NIP-05 domains are fetched in the background (when a pubkey AUTHs, or a lookup
+/// finds them missing or stale), so requests never wait on them. Until a domain is
+/// first fetched, its pubkeys don't match it.
+#[derive(Default)]
+pub struct ProfileRegistry {
+    assignments: RwLock<HashMap<String, String>>,
+    nip05_cache: RwLock<HashMap<String, (Instant, HashSet<String>)>>,
+    nip05_fetching: Mutex<HashSet<String>>,
+    jobs: Mutex<HashMap<String, PubkeyJobs>>,
+}
+
+#[derive(Default)]
+struct PubkeyJobs {
+    running: usize,
+    started: VecDeque<Instant>,
+}
+
+/// Counts against a pubkey's running jobs until dropped
+pub struct JobQuotaGuard {
+    registry: Arc<ProfileRegistry>,
+    pubkey: String,
+}
+
+impl ProfileRegistry {
+
+    pub async fn resolve<'a>(self: &Arc<Self>, app_config: &'a AppConfig, pubkey: &str) -> &'a Profile {
+
+        if let Some(profile) = self.assignments.read().await.get(pubkey).and_then(|name| app_config.profiles.get(name)) {
+            return profile
+        }
+
+        let listed = app_config.profiles
+            .values()
+            .filter(|profile| profile.pubkeys.iter().any(|p| p == pubkey))
+            .max_by_key(|profile| profile.priority);
+
+        if let Some(profile) = listed {
+            return profile
+        }
+
+        let mut nip05_match: Option<&'a Profile> = None;
+        for profile in app_config.profiles.values().filter(|profile| !profile.nip05_domains.is_empty()) {
+            if nip05_match.is_some_and(|matched| matched.priority >= profile.priority) {
+                continue
+            }
+
+            for domain in &profile.nip05_domains {
+                if self.nip05_domain_lists(domain, pubkey).await {
+                    nip05_match = Some(profile);
+                    break
+                }
+            }
+        }
+
+        nip05_match.unwrap_or_else(|| app_config.default_profile())
+    }
+
+    /// Assign a pubkey to a profile at runtime (overrides config file assignments)
+    pub async fn assign(&self, pubkey: &str, profile_name: &str) {
+        self.assignments.write().await.insert(pubkey.to_string(), profile_name.to_string());
+    }
+
+    pub async fn unassign(&self, pubkey: &str) -> Option<String> {
+        self.assignments.write().await.remove(pubkey)
+    }
+
+    pub async fn assignments(&self) -> HashMap<String, String> {
+        self.assignments.read().await.clone()
+    }
+
+    /// Reserve a job against the pubkey's profile quotas
+    pub fn start_job(self: &Arc<Self>, pubkey: &str, profile: &Profile) -> Result<JobQuotaGuard> {
+        let mut jobs = self.jobs.lock().expect("job quota lock poisoned");
+        let pubkey_jobs = jobs.entry(pubkey.to_string()).or_default();
+
+        if let Some(max_concurrent_jobs) = profile.max_concurrent_jobs {
+            if pubkey_jobs.running >= max_concurrent_jobs {
+                return Err(anyhow!("rate-limited: {max_concurrent_jobs} concurrent jobs allowed"))
+            }
+        }
+
+        let now = Instant::now();
+        while pubkey_jobs.started.front().is_some_and(|started| now.duration_since(*started) > JOB_QUOTA_WINDOW) {
+            pubkey_jobs.started.pop_front();
+        }
+
+        if let Some(max_jobs_per_hour) = profile.max_jobs_per_hour {
+            if pubkey_jobs.started.len() >= max_jobs_per_hour {
+                return Err(anyhow!("rate-limited: {max_jobs_per_hour} jobs per hour allowed"))
+            }
+        }
+
+        pubkey_jobs.running += 1;
+        pubkey_jobs.started.push_back(now);
+
+        Ok(JobQuotaGuard {
+            registry: Arc::clone(self),
+            pubkey: pubkey.to_string(),
+        })
+    }
+
+    /// Fetch any missing or stale profile NIP-05 domains in the background, so they're
+    /// ready by the time an authenticated pubkey makes a request
+    pub async fn prefetch_nip05_domains(self: &Arc<Self>, app_config: &AppConfig) {
+        for domain in app_config.profiles.values().flat_map(|profile| &profile.nip05_domains) {
+            let fresh = self.nip05_cache.read().await.get(domain)
+                .is_some_and(|(fetched_at, _)| fetched_at.elapsed() < NIP05_CACHE_TTL);
+
+            if !fresh {
+                self.fetch_nip05_domain(domain);
+            }
+        }
+    }
+
+    // Only the cached pubkeys are checked. A stale cache is still used while it's refetched
+    async fn nip05_domain_lists(self: &Arc<Self>, domain: &str, pubkey: &str) -> bool {
+
+        let (fresh, listed) = match self.nip05_cache.read().await.get(domain) {
+            None => (false, false),
+            Some((fetched_at, pubkeys)) => (fetched_at.elapsed() < NIP05_CACHE_TTL, pubkeys.contains(pubkey)),
+        };
+
+        if !fresh {
+            self.fetch_nip05_domain(domain);
+        }
+
+        listed
+    }
+
+    // Fetch a domain in the background, unless it's already being fetched
+    fn fetch_nip05_domain(self: &Arc<Self>, domain: &str) {
+        if !self.nip05_fetching.lock().expect("NIP-05 fetch lock poisoned").insert(domain.to_string()) {
+            return
+        }
+
+        let registry = Arc::clone(self);
+        let domain = domain.to_string();
+
+        tokio::spawn(async move {
+            // Failed lookups are cached as empty, so a broken domain isn't fetched every request
+            let pubkeys = fetch_nip05_pubkeys(&domain).await.unwrap_or_else(|e| {
+                warn!("NIP-05 lookup for {domain} failed: {e:?}");
+                HashSet::new()
+            });
+
+            registry.nip05_cache.write().await.insert(domain.clone(), (Instant::now(), pubkeys));
+            registry.nip05_fetching.lock().expect("NIP-05 fetch lock poisoned").remove(&domain);
+        });
+    }
+}
+
+impl Drop for JobQuotaGuard {
+    fn drop(&mut self) {
+        let mut jobs = self.registry.jobs.lock().expect("job quota lock poisoned");
+        if let Some(pubkey_jobs) = jobs.get_mut(&self.pubkey) {
+            pubkey_jobs.running = pubkey_jobs.running.saturating_sub(1);
+            if pubkey_jobs.running == 0 && pubkey_jobs.started.is_empty() {
+                jobs.remove(&self.pubkey);
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Nip05Document {
+    #[serde(default)]
+    names: HashMap<String, String>,
+}
+
+// All pubkeys listed in https://<domain>/.well-known/nostr.json
+async fn fetch_nip05_pubkeys(domain: &str) -> Result<HashSet<String>> {
+    let url = format!("https://{domain}/.well-known/nostr.json");
+
+    let document: Nip05Document = reqwest::Client::new()
+        .get(&url)
+        .timeout(NIP05_FETCH_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(document.names.into_values().map(|p| p.to_lowercase()).collect())
+}
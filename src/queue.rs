@@ -0,0 +1,128 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// Limits how many events are mined at once. When all miners are busy, jobs
+/// wait in order of profile priority (highest first), then arrival.
+#[derive(Default)]
+pub struct MiningQueue {
+    inner: Mutex<QueueInner>,
+}
+
+#[derive(Default)]
+struct QueueInner {
+    running: usize,
+    max_running: usize,
+    next_seq: u64,
+    waiting: BinaryHeap<Waiter>,
+}
+
+struct Waiter {
+    priority: u8,
+    seq: u64,
+    tx: oneshot::Sender<()>,
+}
+
+/// A miner reserved for one job, released when dropped
+pub struct MiningSlot {
+    queue: Arc<MiningQueue>,
+}
+
+// Releases a slot that was handed over after the waiting job gave up
+struct PendingSlot {
+    queue: Arc<MiningQueue>,
+    rx: Option<oneshot::Receiver<()>>,
+}
+
+impl MiningQueue {
+
+    /// Wait for a free miner. `max_running` is read from the current config each call
+    pub async fn acquire(self: &Arc<Self>, priority: u8, max_running: usize) -> MiningSlot {
+
+        let rx = {
+            let mut inner = self.inner.lock().expect("mining queue lock poisoned");
+            inner.max_running = max_running.max(1);
+
+            if inner.running < inner.max_running && inner.waiting.is_empty() {
+                inner.running += 1;
+                return MiningSlot { queue: Arc::clone(self) }
+            }
+
+            let (tx, rx) = oneshot::channel();
+            let seq = inner.next_seq;
+            inner.next_seq += 1;
+            inner.waiting.push(Waiter { priority, seq, tx });
+            rx
+        };
+
+        let mut pending = PendingSlot { queue: Arc::clone(self), rx: Some(rx) };
+        if let Some(rx) = pending.rx.as_mut() {
+            // The sender is only dropped without sending if the queue itself is gone
+            let _ = rx.await;
+        }
+        pending.rx = None;
+
+        MiningSlot { queue: Arc::clone(self) }
+    }
+
+    pub fn running(&self) -> usize {
+        self.inner.lock().expect("mining queue lock poisoned").running
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.inner.lock().expect("mining queue lock poisoned").waiting.len()
+    }
+
+    // Hand the slot to the next waiting job, or free it
+    fn release(&self) {
+        let mut inner = self.inner.lock().expect("mining queue lock poisoned");
+
+        if inner.running <= inner.max_running {
+            while let Some(waiter) = inner.waiting.pop() {
+                if waiter.tx.send(()).is_ok() {
+                    return
+                }
+            }
+        }
+
+        inner.running = inner.running.saturating_sub(1);
+    }
+}
+
+impl Drop for MiningSlot {
+    fn drop(&mut self) {
+        self.queue.release();
+    }
+}
+
+impl Drop for PendingSlot {
+    fn drop(&mut self) {
+        if let Some(mut rx) = self.rx.take() {
+            rx.close();
+            if rx.try_recv().is_ok() {
+                self.queue.release();
+            }
+        }
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority).then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
@@ -0,0 +1,194 @@
+use anyhow::{anyhow, Result};
+use crate::config::RegistrySettings;
+use crate::get_timestamp;
+use crate::pow::{verify, Verification};
+use nostr_rs_relay::event::Event;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::task;
+use tokio::time::Duration;
+
+/// How often expired records are dropped from memory (the file is compacted on open)
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// An event the service mined
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MinedRecord {
+    /// Id of the mined event
+    pub event_id: String,
+    /// Id of the event as submitted, before mining
+    pub source_event_id: String,
+    /// Who requested (and paid for) the work
+    pub pubkey: String,
+    pub difficulty: u16,
+    pub price_sat: u32,
+    /// When the event was mined
+    pub created_at: u64,
+    pub job_id: String,
+    pub event: Event,
+}
+
+/// VERIFY result: the NIP-13 verification, and whether the event was mined here
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MinedVerification {
+    #[serde(flatten)]
+    pub verification: Verification,
+    pub mined_here: bool,
+}
+
+/// Every event mined within the retention period, by id, so paid work can be recognised
+/// (for PUBLISH and VERIFY) and repeat requests served without charging again.
+/// Kept in memory, and appended to a JSON lines file when one is configured
+pub struct MinedRegistry {
+    inner: Mutex<RegistryInner>,
+    // Appended to off the async runtime
+    file: Option<Arc<Mutex<File>>>,
+    // None keeps records forever
+    retention_sec: Option<u64>,
+}
+
+#[derive(Default)]
+struct RegistryInner {
+    records: HashMap<String, MinedRecord>,
+    // (pubkey, source event id) to the mined event id
+    by_source: HashMap<(String, String), String>,
+}
+
+impl Default for MinedRegistry {
+    fn default() -> Self {
+        Self { inner: Mutex::new(RegistryInner::default()), file: None, retention_sec: None }
+    }
+}
+
+impl RegistryInner {
+    fn insert(&mut self, record: MinedRecord) {
+        self.by_source.insert((record.pubkey.clone(), record.source_event_id.clone()), record.event_id.clone());
+        self.records.insert(record.event_id.clone(), record);
+    }
+}
+
+impl MinedRegistry {
+
+    /// Load the configured registry file, dropping expired records, and keep appending to it.
+    /// In memory only when no file is configured
+    pub fn open(settings: &RegistrySettings) -> Result<Self> {
+        let retention_sec = (settings.retention_sec > 0).then_some(settings.retention_sec);
+
+        let path = match &settings.file {
+            None => return Ok(Self { retention_sec, ..Self::default() }),
+            Some(path) => path,
+        };
+
+        let mut inner = RegistryInner::default();
+        for record in load_records(path)? {
+            if !is_expired(&record, retention_sec) {
+                inner.insert(record);
+            }
+        }
+
+        // Rewrite the file without expired records, then append from there
+        let compacted_path = path.with_extension("compacting");
+        {
+            let mut compacted = File::create(&compacted_path)
+                .map_err(|e| anyhow!("Unable to write mined registry {compacted_path:?}: {e}"))?;
+            for record in inner.records.values() {
+                writeln!(compacted, "{}", serde_json::to_string(record)?)?;
+            }
+        }
+        std::fs::rename(&compacted_path, path)?;
+
+        let file = OpenOptions::new().append(true).open(path)
+            .map_err(|e| anyhow!("Unable to open mined registry {path:?}: {e}"))?;
+
+        info!("Mined registry: {path:?} ({} records)", inner.records.len());
+
+        Ok(Self { inner: Mutex::new(inner), file: Some(Arc::new(Mutex::new(file))), retention_sec })
+    }
+
+    /// Add a record, appending it to the registry file (if any) on a blocking thread
+    pub async fn insert(&self, record: MinedRecord) {
+        if let Some(file) = &self.file {
+            let file = Arc::clone(file);
+            let line = serde_json::to_string(&record);
+
+            let written = task::spawn_blocking(move || {
+                let mut file = file.lock().expect("mined registry file lock poisoned");
+                writeln!(file, "{}", line?).map_err(anyhow::Error::from)
+            }).await.map_err(anyhow::Error::from).and_then(|written| written);
+
+            if let Err(e) = written {
+                error!("Writing mined registry record failed: {e:?} {}", &record.event_id);
+            }
+        }
+
+        self.inner.lock().expect("mined registry lock poisoned").insert(record);
+    }
+
+    /// Drop expired records from memory. Run every PRUNE_INTERVAL
+    pub fn prune(&self) {
+        let retention_sec = self.retention_sec;
+        if retention_sec.is_none() {
+            return
+        }
+
+        let mut inner = self.inner.lock().expect("mined registry lock poisoned");
+        let RegistryInner { records, by_source } = &mut *inner;
+
+        records.retain(|_, record| !is_expired(record, retention_sec));
+        by_source.retain(|_, event_id| records.contains_key(event_id));
+    }
+
+    /// The record for a mined event id, if we mined it within the retention period
+    /// Verify a mined event, and check it's one we mined (and haven't expired)
+    pub fn verify(&self, event: &Event) -> MinedVerification {
+        MinedVerification {
+            verification: verify(event),
+            mined_here: self.get(&event.id).is_some(),
+        }
+    }
+
+    pub fn get(&self, event_id: &str) -> Option<MinedRecord> {
+        let inner = self.inner.lock().expect("mined registry lock poisoned");
+        inner.records.get(event_id).filter(|record| !is_expired(record, self.retention_sec)).cloned()
+    }
+
+    /// A previous result for the same submitted event and pubkey, mined to at least `min_difficulty`
+    pub fn cached(&self, pubkey: &str, source_event_id: &str, min_difficulty: u16) -> Option<MinedRecord> {
+        let inner = self.inner.lock().expect("mined registry lock poisoned");
+        inner.by_source.get(&(pubkey.to_string(), source_event_id.to_string()))
+            .and_then(|event_id| inner.records.get(event_id))
+            .filter(|record| record.difficulty >= min_difficulty && !is_expired(record, self.retention_sec))
+            .cloned()
+    }
+}
+
+fn is_expired(record: &MinedRecord, retention_sec: Option<u64>) -> bool {
+    retention_sec.is_some_and(|retention_sec| record.created_at.saturating_add(retention_sec) < get_timestamp())
+}
+
+fn load_records(path: &Path) -> Result<Vec<MinedRecord>> {
+    let file = match File::open(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(anyhow!("Unable to read mined registry {path:?}: {e}")),
+        Ok(file) => file,
+    };
+
+    let mut records = vec![];
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("Skipping invalid mined registry record on line {}: {e}", index + 1),
+        }
+    }
+
+    Ok(records)
+}
@@ -0,0 +1,207 @@
+use anyhow::{anyhow, Result};
+use futures::{SinkExt, StreamExt};
+use nostr_rs_relay::event::Event;
+use reqwest::Url;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::{timeout, Duration};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{client_async_tls, MaybeTlsStream, WebSocketStream};
+
+/// Which relays we connect to on a client's behalf (PUBLISH and bunker relays). Otherwise a
+/// client could have us connect to services on our own network, so only wss:// relays on
+/// public addresses are allowed, or with an allowlist, only the listed relay hosts
+#[derive(Debug, Clone, Default)]
+pub struct RelayAccess {
+    /// Relay hosts allowed (on any address, and over ws:// too). Empty allows any public relay
+    pub allowlist: Vec<String>,
+}
+
+impl RelayAccess {
+
+    /// Check a relay url before resolving it. Returns the url, and whether its host is allowlisted
+    pub fn check_url(&self, relay_url: &str) -> Result<(Url, bool)> {
+        let url = Url::parse(relay_url).map_err(|_| anyhow!("invalid relay url {relay_url}"))?;
+
+        if !matches!(url.scheme(), "wss" | "ws") {
+            return Err(anyhow!("invalid relay url {relay_url}"))
+        }
+
+        let host = url.host_str().ok_or_else(|| anyhow!("invalid relay url {relay_url}"))?;
+        let allowlisted = self.allowlist.iter().any(|allowed| allowed.eq_ignore_ascii_case(host));
+
+        if !self.allowlist.is_empty() && !allowlisted {
+            return Err(anyhow!("relay {host} isn't allowed"))
+        }
+
+        if !allowlisted && url.scheme() != "wss" {
+            return Err(anyhow!("relay url must use wss://"))
+        }
+
+        Ok((url, allowlisted))
+    }
+
+    /// The addresses to connect to for a relay url, once checked. Only the checked
+    /// addresses are connected to, so the host can't resolve somewhere else later
+    pub async fn resolve(&self, relay_url: &str) -> Result<Vec<SocketAddr>> {
+        let (url, allowlisted) = self.check_url(relay_url)?;
+
+        let port = url.port_or_known_default().ok_or_else(|| anyhow!("invalid relay url {relay_url}"))?;
+        // IPv6 hosts are bracketed in urls
+        let host = url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+
+        let addrs: Vec<SocketAddr> = lookup_host((host, port)).await
+            .map_err(|e| anyhow!("unable to resolve {host}: {e}"))?
+            .collect();
+
+        if addrs.is_empty() {
+            return Err(anyhow!("unable to resolve {relay_url}"))
+        }
+
+        if !allowlisted && addrs.iter().any(|addr| !is_public_ip(addr.ip())) {
+            return Err(anyhow!("relay {relay_url} isn't on a public address"))
+        }
+
+        Ok(addrs)
+    }
+}
+
+/// Excludes loopback, private, link-local, shared (CGNAT), multicast and other
+/// special purpose ranges
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ipv4(ip),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8 "this network"
+        || a == 0
+        // 100.64.0.0/10 shared address space
+        || (a == 100 && (64..128).contains(&b))
+        // 192.0.0.0/24 protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // 198.18.0.0/15 benchmarking
+        || (a == 198 && (18..20).contains(&b))
+        // 240.0.0.0/4 reserved
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let first_segment = ip.segments()[0];
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // fc00::/7 unique local
+        || (first_segment & 0xfe00) == 0xfc00
+        // fe80::/10 link-local
+        || (first_segment & 0xffc0) == 0xfe80
+        // 2001:db8::/32 documentation
+        || (first_segment == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+/// An outbound websocket connection to a Nostr relay
+pub struct RelayConnection {
+    pub url: String,
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl RelayConnection {
+    pub async fn connect(url: &str, relay_access: &RelayAccess) -> Result<Self> {
+        let addrs = relay_access.resolve(url).await?;
+
+        let stream = TcpStream::connect(&addrs[..]).await
+            .map_err(|e| anyhow!("unable to connect to {url}: {e}"))?;
+
+        let (ws, _) = client_async_tls(url, stream).await
+            .map_err(|e| anyhow!("unable to connect to {url}: {e}"))?;
+
+        Ok(Self { url: url.to_string(), ws })
+    }
+
+    /// Send a relay message, e.g. `["EVENT", {...}]`
+    pub async fn send(&mut self, message: &Value) -> Result<()> {
+        self.ws.send(Message::Text(message.to_string())).await
+            .map_err(|e| anyhow!("unable to send to {}: {e}", self.url))
+    }
+
+    /// The next relay message (a JSON array). None once the relay closes the connection
+    pub async fn next_message(&mut self) -> Result<Option<Vec<Value>>> {
+        while let Some(message) = self.ws.next().await {
+            let text = match message.map_err(|e| anyhow!("unable to read from {}: {e}", self.url))? {
+                Message::Text(text) => text,
+                Message::Close(_) => return Ok(None),
+                // Pings are answered by tungstenite
+                _ => continue,
+            };
+
+            match serde_json::from_str::<Value>(&text) {
+                Ok(Value::Array(message)) => return Ok(Some(message)),
+                _ => debug!("Ignoring unexpected message from {}: {text}", self.url),
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub async fn close(mut self) {
+        let _ = self.ws.close(None).await;
+    }
+}
+
+/// A relay's reply to a published event
+#[derive(Serialize, Debug, Clone)]
+pub struct PublishResult {
+    pub relay: String,
+    pub ok: bool,
+    /// The relay's OK message, or why publishing failed
+    pub message: String,
+}
+
+/// Publish an event to every relay at once, waiting up to `publish_timeout` for each relay's OK
+pub async fn publish_event(relays: &[String], event: &Event, publish_timeout: Duration, relay_access: &RelayAccess) -> Vec<PublishResult> {
+    let publishes = relays.iter().map(|relay| async move {
+        let result = timeout(publish_timeout, publish_to_relay(relay, event, relay_access)).await
+            .unwrap_or_else(|_| Err(anyhow!("timed out")));
+
+        match result {
+            Ok((ok, message)) => PublishResult { relay: relay.clone(), ok, message },
+            Err(e) => PublishResult { relay: relay.clone(), ok: false, message: format!("error: {e}") },
+        }
+    });
+
+    futures::future::join_all(publishes).await
+}
+
+async fn publish_to_relay(url: &str, event: &Event, relay_access: &RelayAccess) -> Result<(bool, String)> {
+    let mut relay = RelayConnection::connect(url, relay_access).await?;
+    relay.send(&json!(["EVENT", event])).await?;
+
+    while let Some(message) = relay.next_message().await? {
+        if let [kind, event_id, ok, rest @ ..] = message.as_slice() {
+            if kind == "OK" && event_id == event.id.as_str() {
+                let message = rest.first().and_then(Value::as_str).unwrap_or_default().to_string();
+                relay.close().await;
+                return Ok((ok.as_bool().unwrap_or(false), message))
+            }
+        }
+    }
+
+    Err(anyhow!("relay closed the connection"))
+}
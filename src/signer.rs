@@ -0,0 +1,446 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use cbc::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use chacha20::ChaCha20;
+use chacha20::cipher::StreamCipher;
+use crate::get_timestamp;
+use crate::pow::get_content_id;
+use crate::config::is_relay_url;
+use crate::relay::{RelayAccess, RelayConnection};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use nostr_rs_relay::event::Event;
+use nostr_rust::events::EventPrepare;
+use reqwest::Url;
+use secp256k1::{ecdh, KeyPair, Parity, Secp256k1, SecretKey, XOnlyPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::fmt;
+use std::str::FromStr;
+use tokio::time::{sleep, timeout, Duration};
+use uuid::Uuid;
+
+const NIP46_KIND: u16 = 24133;
+
+// Requests are resent with NIP-04 when a remote signer hasn't replied to NIP-44 by then
+const NIP04_FALLBACK_AFTER: Duration = Duration::from_secs(5);
+
+const NIP44_VERSION: u8 = 2;
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+
+/// A NIP-46 bunker connection: `bunker://<remote-signer-pubkey>?relay=<wss://...>&secret=<optional>`
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct BunkerUri {
+    pub remote_pubkey: String,
+    pub relays: Vec<String>,
+    secret: Option<String>,
+}
+
+impl FromStr for BunkerUri {
+    type Err = anyhow::Error;
+
+    fn from_str(uri: &str) -> Result<Self> {
+        let url = Url::parse(uri).map_err(|_| anyhow!("invalid bunker uri"))?;
+
+        if url.scheme() != "bunker" {
+            return Err(anyhow!("bunker uri must start with bunker://"))
+        }
+
+        let remote_pubkey = url.host_str().unwrap_or_default().to_lowercase();
+        if XOnlyPublicKey::from_str(&remote_pubkey).is_err() {
+            return Err(anyhow!("bunker uri has an invalid remote signer pubkey"))
+        }
+
+        let mut relays = vec![];
+        let mut secret = None;
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "relay" if is_relay_url(&value) => relays.push(value.to_string()),
+                "relay" => return Err(anyhow!("bunker uri has an invalid relay url {value}")),
+                "secret" => secret = Some(value.to_string()),
+                _ => {},
+            }
+        }
+
+        if relays.is_empty() {
+            return Err(anyhow!("bunker uri needs at least one relay"))
+        }
+
+        Ok(Self { remote_pubkey, relays, secret })
+    }
+}
+
+impl TryFrom<String> for BunkerUri {
+    type Error = anyhow::Error;
+
+    fn try_from(uri: String) -> Result<Self> {
+        uri.parse()
+    }
+}
+
+impl From<BunkerUri> for String {
+    fn from(bunker: BunkerUri) -> Self {
+        let mut url = Url::parse(&format!("bunker://{}", bunker.remote_pubkey)).expect("valid bunker uri");
+        {
+            let mut query = url.query_pairs_mut();
+            for relay in &bunker.relays {
+                query.append_pair("relay", relay);
+            }
+            if let Some(secret) = &bunker.secret {
+                query.append_pair("secret", secret);
+            }
+        }
+        url.to_string()
+    }
+}
+
+// Requests are logged, so keep the secret out of them
+impl fmt::Debug for BunkerUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BunkerUri")
+            .field("remote_pubkey", &self.remote_pubkey)
+            .field("relays", &self.relays)
+            .field("secret", &self.secret.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// Ask a NIP-46 remote signer to sign a mined event, trying each bunker relay in turn.
+/// The signed event must have the mined event's id and a valid signature
+pub async fn sign_with_bunker(bunker: &BunkerUri, event: &Event, signer_timeout: Duration, relay_access: &RelayAccess) -> Result<Event> {
+    let mut last_error = anyhow!("no bunker relays");
+
+    for relay_url in &bunker.relays {
+        match timeout(signer_timeout, sign_via_relay(bunker, relay_url, event, relay_access)).await {
+            Ok(Ok(signed_event)) => return Ok(signed_event),
+            Ok(Err(e)) => last_error = e,
+            Err(_) => last_error = anyhow!("remote signer timed out on {relay_url}"),
+        }
+        info!("Remote signing of {} via {relay_url} failed: {last_error}", &event.id);
+    }
+
+    Err(last_error)
+}
+
+async fn sign_via_relay(bunker: &BunkerUri, relay_url: &str, event: &Event, relay_access: &RelayAccess) -> Result<Event> {
+    let mut session = SignerSession::connect(bunker, relay_url, relay_access).await?;
+
+    if let Some(secret) = &bunker.secret {
+        session.request("connect", json!([&bunker.remote_pubkey, secret])).await?;
+    }
+
+    // The remote signer adds the id and signature
+    let unsigned_event = json!({
+        "pubkey": &event.pubkey,
+        "created_at": event.created_at,
+        "kind": event.kind,
+        "tags": &event.tags,
+        "content": &event.content,
+    });
+
+    let result = session.request("sign_event", json!([unsigned_event.to_string()])).await;
+    session.relay.close().await;
+
+    let signed_event: Event = serde_json::from_str(&result?)
+        .map_err(|_| anyhow!("remote signer returned an invalid event"))?;
+
+    if signed_event.id != event.id || signed_event.pubkey != event.pubkey {
+        return Err(anyhow!("remote signer changed the event (id {}, expected {})", signed_event.id, event.id))
+    }
+
+    signed_event.validate().map_err(|_| anyhow!("remote signer returned an invalid signature"))?;
+
+    Ok(signed_event)
+}
+
+// How NIP-46 messages are encrypted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encryption {
+    Nip44,
+    Nip04,
+}
+
+// A relay connection with a throwaway client keypair, subscribed to the remote signer's replies
+struct SignerSession {
+    relay: RelayConnection,
+    secp: Secp256k1<secp256k1::All>,
+    keypair: KeyPair,
+    client_pubkey: String,
+    remote_pubkey: String,
+    nip04_key: [u8; 32],
+    nip44_key: [u8; 32],
+    // Whichever the remote signer has replied with. None until its first reply
+    encryption: Option<Encryption>,
+    subscription_id: String,
+}
+
+impl SignerSession {
+    async fn connect(bunker: &BunkerUri, relay_url: &str, relay_access: &RelayAccess) -> Result<Self> {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::new(&mut rand::thread_rng());
+        let keypair = KeyPair::from_secret_key(&secp, &secret_key);
+        let (client_pubkey, _) = keypair.x_only_public_key();
+
+        let remote_pubkey = XOnlyPublicKey::from_str(&bunker.remote_pubkey)?;
+        let nip04_key = nip04_shared_key(&secret_key, &remote_pubkey)?;
+        let nip44_key = nip44_conversation_key(&secret_key, &remote_pubkey)?;
+
+        let mut relay = RelayConnection::connect(relay_url, relay_access).await?;
+
+        let subscription_id = Uuid::new_v4().to_string();
+        let filter = json!({ "kinds": [NIP46_KIND], "#p": [client_pubkey.to_string()], "limit": 0 });
+        relay.send(&json!(["REQ", &subscription_id, filter])).await?;
+
+        Ok(Self {
+            relay,
+            secp,
+            keypair,
+            client_pubkey: client_pubkey.to_string(),
+            remote_pubkey: bunker.remote_pubkey.clone(),
+            nip04_key,
+            nip44_key,
+            encryption: None,
+            subscription_id,
+        })
+    }
+
+    // Send a NIP-46 request and wait for its response. Returns the result.
+    // Requests are NIP-44 encrypted, and resent with NIP-04 if the remote signer doesn't
+    // reply in time (until it has replied to either)
+    async fn request(&mut self, method: &str, params: Value) -> Result<String> {
+        let request_id = Uuid::new_v4().to_string();
+        let payload = json!({ "id": &request_id, "method": method, "params": params }).to_string();
+
+        self.send_request(self.encryption.unwrap_or(Encryption::Nip44), &payload).await?;
+
+        let nip04_fallback = sleep(NIP04_FALLBACK_AFTER);
+        tokio::pin!(nip04_fallback);
+        let mut fallback_pending = self.encryption.is_none();
+
+        loop {
+            let message = tokio::select! {
+                message = self.relay.next_message() => message?,
+                _ = &mut nip04_fallback, if fallback_pending => {
+                    fallback_pending = false;
+                    info!("Remote signer {} hasn't replied to NIP-44, resending {method} with NIP-04", &self.remote_pubkey);
+                    self.send_request(Encryption::Nip04, &payload).await?;
+                    continue
+                },
+            };
+
+            let message = match message {
+                Some(message) => message,
+                None => break,
+            };
+
+            let response_event = match message.as_slice() {
+                [kind, subscription_id, event] if kind == "EVENT" && *subscription_id == self.subscription_id => event,
+                [kind, _, accepted, reason] if kind == "OK" && *accepted == false => {
+                    return Err(anyhow!("relay rejected the signer request: {reason}"))
+                },
+                _ => continue,
+            };
+
+            if response_event.get("pubkey").and_then(Value::as_str) != Some(self.remote_pubkey.as_str()) {
+                continue
+            }
+
+            let content = response_event.get("content").and_then(Value::as_str).unwrap_or_default();
+            let (encryption, response) = match self.decrypt(content) {
+                Some((encryption, plain)) => match serde_json::from_str::<Value>(&plain) {
+                    Ok(response) => (encryption, response),
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+
+            if response.get("id").and_then(Value::as_str) != Some(request_id.as_str()) {
+                continue
+            }
+
+            self.encryption = Some(encryption);
+
+            let result = response.get("result").and_then(Value::as_str);
+            let error = response.get("error").and_then(Value::as_str).filter(|error| !error.is_empty());
+
+            return match (result, error) {
+                (Some("auth_url"), Some(auth_url)) => Err(anyhow!("remote signer needs approval at {auth_url}")),
+                (_, Some(error)) => Err(anyhow!("remote signer {method} failed: {error}")),
+                (Some(result), None) => Ok(result.to_string()),
+                (None, None) => Err(anyhow!("remote signer {method} returned no result")),
+            }
+        }
+
+        Err(anyhow!("relay closed the connection"))
+    }
+
+    async fn send_request(&mut self, encryption: Encryption, payload: &str) -> Result<()> {
+        let content = match encryption {
+            Encryption::Nip44 => nip44_encrypt(&self.nip44_key, payload)?,
+            Encryption::Nip04 => nip04_encrypt(&self.nip04_key, payload),
+        };
+
+        let request_event = self.sign_event(content)?;
+        self.relay.send(&json!(["EVENT", request_event])).await
+    }
+
+    // Replies are decrypted by their format, as NIP-04 content has an "?iv=" suffix
+    fn decrypt(&self, content: &str) -> Option<(Encryption, String)> {
+        if content.contains("?iv=") {
+            nip04_decrypt(&self.nip04_key, content).ok().map(|plain| (Encryption::Nip04, plain))
+        } else {
+            nip44_decrypt(&self.nip44_key, content).ok().map(|plain| (Encryption::Nip44, plain))
+        }
+    }
+
+    // A kind 24133 request to the remote signer, signed by our client keypair
+    fn sign_event(&self, content: String) -> Result<Value> {
+        let event = EventPrepare {
+            pub_key: self.client_pubkey.clone(),
+            created_at: get_timestamp(),
+            kind: NIP46_KIND,
+            tags: vec![vec!["p".to_string(), self.remote_pubkey.clone()]],
+            content,
+        };
+
+        let id = get_content_id(&event);
+        let message = secp256k1::Message::from_slice(&hex::decode(&id)?)?;
+        let sig = self.secp.sign_schnorr_no_aux_rand(&message, &self.keypair);
+
+        Ok(json!({
+            "id": id,
+            "pubkey": event.pub_key,
+            "created_at": event.created_at,
+            "kind": event.kind,
+            "tags": event.tags,
+            "content": event.content,
+            "sig": sig.to_string(),
+        }))
+    }
+}
+
+/// NIP-04 shared key: the x coordinate of the ECDH point
+pub fn nip04_shared_key(secret_key: &SecretKey, pubkey: &XOnlyPublicKey) -> Result<[u8; 32]> {
+    let point = ecdh::shared_secret_point(&pubkey.public_key(Parity::Even), secret_key);
+    Ok(point[..32].try_into()?)
+}
+
+/// NIP-04: base64(AES-256-CBC ciphertext) + "?iv=" + base64(iv)
+pub fn nip04_encrypt(shared_key: &[u8; 32], plaintext: &str) -> String {
+    let iv: [u8; 16] = rand::random();
+    let ciphertext = Aes256CbcEnc::new(shared_key.into(), &iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(plaintext.as_bytes());
+
+    let base64 = base64::engine::general_purpose::STANDARD;
+    format!("{}?iv={}", base64.encode(ciphertext), base64.encode(iv))
+}
+
+pub fn nip04_decrypt(shared_key: &[u8; 32], content: &str) -> Result<String> {
+    let (ciphertext, iv) = content.split_once("?iv=").ok_or_else(|| anyhow!("missing iv"))?;
+
+    let base64 = base64::engine::general_purpose::STANDARD;
+    let ciphertext = base64.decode(ciphertext)?;
+    let iv: [u8; 16] = base64.decode(iv)?.try_into().map_err(|_| anyhow!("invalid iv"))?;
+
+    let plaintext = Aes256CbcDec::new(shared_key.into(), &iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(&ciphertext)
+        .map_err(|_| anyhow!("unable to decrypt"))?;
+
+    Ok(String::from_utf8(plaintext)?)
+}
+
+/// NIP-44 (v2) conversation key: HKDF-extract of the ECDH x coordinate, with salt "nip44-v2"
+pub fn nip44_conversation_key(secret_key: &SecretKey, pubkey: &XOnlyPublicKey) -> Result<[u8; 32]> {
+    let shared_x = nip04_shared_key(secret_key, pubkey)?;
+    let (conversation_key, _) = Hkdf::<Sha256>::extract(Some(b"nip44-v2"), &shared_x);
+    Ok(conversation_key.as_slice().try_into()?)
+}
+
+/// NIP-44 (v2): base64(version + nonce + ChaCha20 ciphertext of the padded plaintext + HMAC-SHA256)
+pub fn nip44_encrypt(conversation_key: &[u8; 32], plaintext: &str) -> Result<String> {
+    if plaintext.is_empty() || plaintext.len() > u16::MAX as usize {
+        return Err(anyhow!("invalid plaintext length"))
+    }
+
+    let nonce: [u8; 32] = rand::random();
+    let (chacha_key, chacha_nonce, hmac_key) = nip44_message_keys(conversation_key, &nonce)?;
+
+    // The plaintext length (u16, big endian), the plaintext, then zeros
+    let mut ciphertext = (plaintext.len() as u16).to_be_bytes().to_vec();
+    ciphertext.extend(plaintext.as_bytes());
+    ciphertext.resize(2 + nip44_padded_len(plaintext.len()), 0);
+
+    ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut ciphertext);
+    let mac = nip44_mac(&hmac_key, &nonce, &ciphertext)?.finalize().into_bytes();
+
+    let mut payload = vec![NIP44_VERSION];
+    payload.extend(nonce);
+    payload.extend(ciphertext);
+    payload.extend(mac);
+
+    Ok(base64::engine::general_purpose::STANDARD.encode(payload))
+}
+
+pub fn nip44_decrypt(conversation_key: &[u8; 32], payload: &str) -> Result<String> {
+    if payload.starts_with('#') {
+        return Err(anyhow!("unsupported encryption version"))
+    }
+    if !(132..=87472).contains(&payload.len()) {
+        return Err(anyhow!("invalid payload length"))
+    }
+
+    let data = base64::engine::general_purpose::STANDARD.decode(payload)?;
+    if !(99..=65603).contains(&data.len()) {
+        return Err(anyhow!("invalid payload length"))
+    }
+    if data[0] != NIP44_VERSION {
+        return Err(anyhow!("unsupported encryption version {}", data[0]))
+    }
+
+    let nonce: [u8; 32] = data[1..33].try_into()?;
+    let (ciphertext, mac) = data[33..].split_at(data.len() - 65);
+
+    let (chacha_key, chacha_nonce, hmac_key) = nip44_message_keys(conversation_key, &nonce)?;
+    nip44_mac(&hmac_key, &nonce, ciphertext)?.verify_slice(mac).map_err(|_| anyhow!("invalid mac"))?;
+
+    let mut padded = ciphertext.to_vec();
+    ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut padded);
+
+    let len = u16::from_be_bytes([padded[0], padded[1]]) as usize;
+    if len == 0 || padded.len() != 2 + nip44_padded_len(len) {
+        return Err(anyhow!("invalid padding"))
+    }
+
+    Ok(String::from_utf8(padded[2..2 + len].to_vec())?)
+}
+
+// The ChaCha20 key and nonce, and the HMAC key, for one message
+fn nip44_message_keys(conversation_key: &[u8; 32], nonce: &[u8; 32]) -> Result<([u8; 32], [u8; 12], [u8; 32])> {
+    let mut keys = [0u8; 76];
+    Hkdf::<Sha256>::from_prk(conversation_key).map_err(|_| anyhow!("invalid conversation key"))?
+        .expand(nonce, &mut keys).map_err(|_| anyhow!("invalid message keys length"))?;
+
+    Ok((keys[..32].try_into()?, keys[32..44].try_into()?, keys[44..].try_into()?))
+}
+
+fn nip44_mac(hmac_key: &[u8; 32], nonce: &[u8; 32], ciphertext: &[u8]) -> Result<Hmac<Sha256>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(hmac_key).map_err(|_| anyhow!("invalid hmac key"))?;
+    mac.update(nonce);
+    mac.update(ciphertext);
+    Ok(mac)
+}
+
+// Plaintexts are padded to 32 bytes, then to a power of two or an eighth of one
+fn nip44_padded_len(len: usize) -> usize {
+    if len <= 32 {
+        return 32
+    }
+
+    let next_power = 1 << (usize::BITS - (len - 1).leading_zeros());
+    let chunk = if next_power <= 256 { 32 } else { next_power / 8 };
+    chunk * ((len - 1) / chunk + 1)
+}
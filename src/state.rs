@@ -0,0 +1,50 @@
+use crate::admin::AdminStore;
+use crate::audit::AuditLog;
+use crate::auth::SeenAuthEvents;
+use crate::calibration::Calibration;
+use crate::config::ConfigStore;
+use crate::delegation::GrantStore;
+use crate::health::Health;
+use crate::jobs::JobRegistry;
+use crate::peer::PeerRegistry;
+use crate::profile::ProfileRegistry;
+use crate::queue::MiningQueue;
+use crate::registry::MinedRegistry;
+use std::sync::Arc;
+
+/// Service wide state shared by every connection. Unlike `AppConfig`, it lives
+/// for the life of the process and is kept across config reloads.
+pub struct AppState {
+    pub config: Arc<ConfigStore>,
+    pub profiles: Arc<ProfileRegistry>,
+    pub mining_queue: Arc<MiningQueue>,
+    pub jobs: JobRegistry,
+    pub peers: PeerRegistry,
+    pub health: Health,
+    pub calibration: Calibration,
+    pub audit: AuditLog,
+    pub registry: MinedRegistry,
+    pub grants: GrantStore,
+    pub admin: AdminStore,
+    /// NIP-98 events already used on the HTTP API
+    pub http_auth_events: SeenAuthEvents,
+}
+
+impl AppState {
+    pub fn new(config: Arc<ConfigStore>, audit: AuditLog, registry: MinedRegistry, grants: GrantStore, admin: AdminStore) -> Self {
+        Self {
+            config,
+            profiles: Arc::new(ProfileRegistry::default()),
+            mining_queue: Arc::new(MiningQueue::default()),
+            jobs: JobRegistry::default(),
+            peers: PeerRegistry::default(),
+            health: Health::default(),
+            calibration: Calibration::default(),
+            audit,
+            registry,
+            grants,
+            admin,
+            http_auth_events: SeenAuthEvents::default(),
+        }
+    }
+}
@@ -1,22 +1,32 @@
-use anyhow::Result;
-use crate::commands::{NostrMessage, AuthCmd, PowCmd};
+use anyhow::{anyhow, Result};
+use crate::admin::handle_admin_request;
+use crate::audit::{AuditAction, AuditRecord};
+use crate::commands::{NostrMessage, AdminCmd, AuthCmd, PowCmd, PublishCmd, VerifyCmd};
 use crate::config::AppConfig;
 use crate::NEXT_USERID;
-use crate::payment::{debt_account, credit_account, payment_required};
+use crate::jobs::{prepare_job, run_job, JobStatus};
+use crate::metrics::metrics;
 use crate::peer::PeerInfo;
-use crate::pow::{generate_pow, validate_pow_request};
+use crate::relay::publish_event;
+use crate::state::AppState;
 use futures::{StreamExt, SinkExt};
+use serde_json::json;
+use std::collections::VecDeque;
 use std::net::IpAddr;
 use std::sync::Arc;
 use std::sync::atomic::Ordering;
 use tokio::sync::{RwLock, mpsc, mpsc::error::SendTimeoutError};
-use tokio::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant, Interval, MissedTickBehavior, sleep_until};
 use warp::ws::{Message, WebSocket};
 
 const MPSC_SEND_TIMEOUT: Duration = Duration::from_millis(20);
 
+// Requests received while another is handled wait for it, up to this many
+const MAX_PENDING_REQUESTS: usize = 10;
 
-pub async fn ws_connect(ws: WebSocket, app_config: Arc<AppConfig>, real_ip: Option<IpAddr>) {
+
+pub async fn ws_connect(ws: WebSocket, state: Arc<AppState>, real_ip: Option<IpAddr>) {
 
     let peer_id = NEXT_USERID.fetch_add(1, Ordering::Relaxed);
     info!("New connection: {peer_id} from {real_ip:?}");
@@ -26,6 +36,12 @@ pub async fn ws_connect(ws: WebSocket, app_config: Arc<AppConfig>, real_ip: Opti
     // Split websocket connection
     let (mut ws_tx, mut ws_rx) = ws.split();
 
+    if state.peers.is_banned(None, real_ip).await {
+        info!("Closing banned connection: {peer_id} from {real_ip:?}");
+        let _ = ws_tx.send(Message::close_with(1008u16, "blocked: banned")).await;
+        return
+    }
+
     // Peer websocket outbox (peer will drop messages if they aren't taking after this limit)
     let (peer_tx, mut peer_rx) = mpsc::channel::<Message>(100);
 
@@ -39,13 +55,72 @@ pub async fn ws_connect(ws: WebSocket, app_config: Arc<AppConfig>, real_ip: Opti
         return
     }
 
+    let connected_at = Instant::now();
+
+    metrics().connections.inc();
+
+    // Fires when an admin kicks or bans this peer
+    let kicked = state.peers.register(Arc::clone(&peer_info)).await;
+
+    // Keepalive settings are fixed for the life of the connection
+    let websocket_config = state.config.current().await.websocket.clone();
+
+    // Keepalive pings (None when disabled)
+    let mut ping_interval = websocket_config.ping_interval.map(|period| {
+        let mut interval = tokio::time::interval_at(connected_at + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+    let mut ping_sent_at: Option<Instant> = None;
+    let mut last_activity = connected_at;
+
+    // Requests are handled one at a time (in order) in their own task, so we keep
+    // reading frames, pinging and watching for kicks while a job mines
+    let mut request: Option<JoinHandle<Result<()>>> = None;
+    let mut pending_requests: VecDeque<Message> = VecDeque::new();
+
     'run_loop: loop {
 
-        let peer_info = Arc::clone(&peer_info);
-        let peer_tx = peer_tx.clone();
+        // Unauthenticated peers get a fixed window from connect to complete AUTH,
+        // authenticated peers are only dropped once idle between requests
+        let authenticated = peer_info.read().await.is_authenticated();
+        let idle_deadline = match authenticated {
+            false => connected_at + websocket_config.auth_timeout,
+            true => last_activity + websocket_config.idle_timeout,
+        };
+        let pong_deadline = ping_sent_at.map(|sent_at| sent_at + websocket_config.pong_timeout);
 
         tokio::select! {
 
+            // Drop peers that are idle (between requests) or haven't completed AUTH in time
+            _ = sleep_until(idle_deadline), if request.is_none() || !authenticated => {
+                info!("Closing idle connection: {peer_id} from {real_ip:?}");
+                let _ = ws_tx.send(Message::close_with(1000u16, "idle timeout")).await;
+                break 'run_loop;
+            },
+
+            // Drop peers kicked or banned by an admin
+            _ = kicked.notified() => {
+                info!("Kicking connection: {peer_id} from {real_ip:?}");
+                let _ = ws_tx.send(Message::close_with(1008u16, "kicked")).await;
+                break 'run_loop;
+            },
+
+            // Drop peers that haven't answered our last ping
+            _ = sleep_until(pong_deadline.unwrap_or(idle_deadline)), if pong_deadline.is_some() => {
+                info!("Ping timeout. Disconnecting dead peer: {peer_id} from {real_ip:?}");
+                break 'run_loop;
+            },
+
+            // Send keepalive ping
+            _ = next_ping(&mut ping_interval), if ping_sent_at.is_none() => {
+                if let Err(e) = ws_tx.send(Message::ping(Vec::new())).await {
+                    debug!("Writing ping to websocket failed. Closing connection: {e:?}");
+                    break 'run_loop
+                }
+                ping_sent_at = Some(Instant::now());
+            },
+
             // Handle peer inbound (inbox) message
             result = ws_rx.next() => {
 
@@ -66,19 +141,41 @@ pub async fn ws_connect(ws: WebSocket, app_config: Arc<AppConfig>, real_ip: Opti
 
                     Some(Ok(result)) => {
 
+                        // Any frame from the peer proves it's still alive
+                        ping_sent_at = None;
+
                         if result.is_close() {
                             break 'run_loop;
                         }
 
-                        // TODO: Currently we only return Ok(()). Add bad peer protection here
-                        if let Err(e) = handle_rx_message(Arc::clone(&peer_info), peer_tx, result, Arc::clone(&app_config)).await {
-                            info!("Disconnecting peer: {:?} - {e:?}", peer_info.read().await.real_ip);
-                            break 'run_loop;
+                        if result.is_ping() || result.is_pong() {
+                            continue 'run_loop;
+                        }
+
+                        match request {
+                            None => request = Some(spawn_request(&state, &peer_info, &peer_tx, result)),
+                            Some(_) if pending_requests.len() < MAX_PENDING_REQUESTS => pending_requests.push_back(result),
+                            Some(_) => send_notice(peer_tx.clone(), "rate-limited: too many pending requests").await,
                         }
                     },
                 }
             },
 
+            // The current request is done, so start the next one
+            handled = request_finished(&mut request) => {
+
+                // TODO: Currently we only return Ok(()). Add bad peer protection here
+                if let Err(e) = handled {
+                    info!("Disconnecting peer: {:?} - {e:?}", peer_info.read().await.real_ip);
+                    break 'run_loop;
+                }
+
+                // Only start the idle clock once we're done with requests (including mining)
+                last_activity = Instant::now();
+
+                request = pending_requests.pop_front().map(|msg| spawn_request(&state, &peer_info, &peer_tx, msg));
+            },
+
             // Handle peer outbound (outbox) message
             Some(result) = peer_rx.recv() => {
 
@@ -93,10 +190,56 @@ pub async fn ws_connect(ws: WebSocket, app_config: Arc<AppConfig>, real_ip: Opti
             },
         }
     }
+
+    // Stop mining for a peer that's gone. The job refunds as it would for any cancellation
+    if let Some(job) = peer_info.read().await.job_id.as_ref().and_then(|job_id| state.jobs.get(job_id)) {
+        if job.cancel() {
+            info!("Cancelled job {} for closed connection: {peer_id}", &job.id);
+        }
+    }
+
+    state.peers.remove(peer_id).await;
+
+    metrics().connections.dec();
+    let authenticated_pubkeys = peer_info.read().await.pubkeys.len();
+    if authenticated_pubkeys > 0 {
+        metrics().authenticated_peers.dec();
+        metrics().authenticated_pubkeys.sub(authenticated_pubkeys as i64);
+    }
+}
+
+// Resolves on the next keepalive tick, or never if pings are disabled
+async fn next_ping(ping_interval: &mut Option<Interval>) {
+    match ping_interval {
+        Some(interval) => { interval.tick().await; },
+        None => futures::future::pending::<()>().await,
+    }
+}
+
+// Resolves when the current request has been handled, or never if there isn't one
+async fn request_finished(request: &mut Option<JoinHandle<Result<()>>>) -> Result<()> {
+    let handled = match request {
+        Some(handle) => handle.await,
+        None => futures::future::pending().await,
+    };
+    *request = None;
+
+    handled.unwrap_or_else(|e| Err(anyhow!("request handler failed: {e}")))
+}
+
+fn spawn_request(state: &Arc<AppState>, peer_info: &Arc<RwLock<PeerInfo>>, peer_tx: &mpsc::Sender<Message>, msg: Message) -> JoinHandle<Result<()>> {
+    let (state, peer_info, peer_tx) = (Arc::clone(state), Arc::clone(peer_info), peer_tx.clone());
+
+    tokio::spawn(async move {
+        // Each request sees the latest config (it may have been reloaded)
+        let app_config = state.config.current().await;
+        handle_rx_message(state, peer_info, peer_tx, msg, app_config).await
+    })
 }
 
 // Handle peer worker inbox
-async fn handle_rx_message(peer_info: Arc<RwLock<PeerInfo>>,
+async fn handle_rx_message(state: Arc<AppState>,
+                           peer_info: Arc<RwLock<PeerInfo>>,
                            peer_tx: mpsc::Sender<Message>,
                            msg: Message,
                            app_config: Arc<AppConfig>,
@@ -117,12 +260,25 @@ async fn handle_rx_message(peer_info: Arc<RwLock<PeerInfo>>,
 
             Ok(NostrMessage::AuthMsg(auth_msg)) => {
                 info!("AUTH Message: {auth_msg:?}");
-                handle_auth_msg(app_config, peer_info, auth_msg, peer_tx).await?;
+                handle_auth_msg(state, app_config, peer_info, auth_msg, peer_tx).await?;
             },
 
             Ok(NostrMessage::PowMsg(pow_msg)) => {
                 info!("POW Message: {pow_msg:?}");
-                handle_pow_msg(app_config, peer_info, pow_msg, peer_tx).await?;
+                handle_pow_msg(state, app_config, peer_info, pow_msg, peer_tx).await?;
+            },
+
+            Ok(NostrMessage::VerifyMsg(verify_msg)) => {
+                handle_verify_msg(state, verify_msg, peer_tx).await;
+            },
+
+            Ok(NostrMessage::PublishMsg(publish_msg)) => {
+                info!("PUBLISH Message: {}", &publish_msg.event.id);
+                handle_publish_msg(state, app_config, peer_info, publish_msg, peer_tx).await?;
+            },
+
+            Ok(NostrMessage::AdminMsg(admin_msg)) => {
+                handle_admin_msg(state, app_config, peer_info, admin_msg, peer_tx).await?;
             },
         }
     }
@@ -131,6 +287,7 @@ async fn handle_rx_message(peer_info: Arc<RwLock<PeerInfo>>,
 }
 
 async fn handle_auth_msg(
+        state: Arc<AppState>,
         app_config: Arc<AppConfig>,
         peer_info: Arc<RwLock<PeerInfo>>,
         auth_msg: AuthCmd,
@@ -141,12 +298,42 @@ async fn handle_auth_msg(
 
     let mut peer_info = peer_info.write().await;
 
-    match peer_info.check_auth_response(app_config.relay_identifier.clone(), &auth_event) {
+    let already_authenticated = peer_info.is_authenticated();
+    let new_pubkey = !peer_info.pubkeys.contains(&auth_event.pubkey);
+    let real_ip = peer_info.real_ip;
+
+    let audit_record = |outcome: &str| AuditRecord {
+        pubkey: Some(auth_event.pubkey.clone()),
+        ip: real_ip,
+        event_id: Some(auth_event.id.clone()),
+        ..AuditRecord::new(AuditAction::Auth, outcome)
+    };
+
+    match peer_info.check_auth_response(&app_config, &auth_event) {
         Err(e) => {
+            metrics().auth_failures.with_label_values(&["websocket"]).inc();
+            state.audit.record(AuditRecord { message: Some(e.to_string()), ..audit_record("failed") });
             let notice_msg = format!("Invalid AUTH response for challenge: {} - {e:?}", peer_info.auth_challenge);
             send_notice(peer_tx, &notice_msg).await
         },
+        Ok(_) if state.peers.is_banned(Some(&auth_event.pubkey), real_ip).await => {
+            if new_pubkey {
+                peer_info.pubkeys.remove(&auth_event.pubkey);
+            }
+            state.audit.record(AuditRecord { message: Some("banned".to_string()), ..audit_record("failed") });
+            send_notice(peer_tx, "blocked: banned").await;
+            return Err(anyhow!("banned pubkey {}", &auth_event.pubkey))
+        },
+
         Ok(_) => {
+            if !already_authenticated {
+                metrics().authenticated_peers.inc();
+            }
+            if new_pubkey {
+                metrics().authenticated_pubkeys.inc();
+            }
+            state.audit.record(audit_record("ok"));
+            state.profiles.prefetch_nip05_domains(&app_config).await;
             let notice_msg = format!("Authorised: {}", &auth_event.pubkey);
             send_notice(peer_tx, &notice_msg).await
         }
@@ -156,75 +343,178 @@ async fn handle_auth_msg(
 }
 
 async fn handle_pow_msg(
+        state: Arc<AppState>,
         app_config: Arc<AppConfig>,
         peer_info: Arc<RwLock<PeerInfo>>,
         pow_msg: PowCmd,
         peer_tx: mpsc::Sender<Message>
     ) -> Result<()> {
 
-    let peer_info = peer_info.read().await;
+    // Requested (and billed) as whichever authenticated pubkey the event is for
+    let (authenticated_pubkey, real_ip) = {
+        let peer_info = peer_info.read().await;
 
-    if let false = peer_info.auth_confirmed {
-        send_notice(peer_tx, "restricted: you need to authorise to confirm your pubkey first").await;
-        return Ok(())
-    }
+        match peer_info.requesting_pubkey(&pow_msg.event, &state.grants) {
+            Some(pubkey) => (pubkey, peer_info.real_ip),
+            None => {
+                send_notice(peer_tx, "restricted: you need to authorise to confirm your pubkey first").await;
+                return Ok(())
+            },
+        }
+    };
 
-    let authenticated_pubkey = peer_info.pubkey.clone().unwrap_or_default();
+    let preserve_created_at = pow_msg.options.preserve_created_at;
 
-    if let Err(e) = validate_pow_request(
-                        app_config.min_pow_difficulty,
-                        app_config.max_pow_difficulty,
-                        pow_msg.target_pow,
-                        &pow_msg.event,
-                        &authenticated_pubkey
-        ) {
+    let prepared_job = match prepare_job(&state, &app_config, &authenticated_pubkey, real_ip, pow_msg.target_pow, pow_msg.event, pow_msg.options).await {
+        Ok(prepared_job) => prepared_job,
+        Err(e) => {
+            send_notice(peer_tx, &format!("pow: {e}")).await;
+            return Ok(())
+        },
+    };
 
-        send_notice(peer_tx, &format!("pow: invalid pow request: {e:?}")).await;
-        return Ok(())
+    if prepared_job.job.target_pow != pow_msg.target_pow {
+        send_notice(peer_tx.clone(), &format!("pow: target difficulty adjusted to {}", prepared_job.job.target_pow)).await;
     }
 
-    let payment_required = payment_required(&app_config.pubkey_whitelist, authenticated_pubkey);
+    // Acknowledge the job, with its ETA and time budget
+    let accepted_str = json!(["POW_ACCEPTED", prepared_job.job.summary()]).to_string();
+    send_msg(peer_tx.clone(), &accepted_str).await;
 
-    // TODO: Need to add logging / record keeping here for financial and troubleshooting
+    // Cancelled if the connection closes while it's mining
+    peer_info.write().await.job_id = Some(prepared_job.job.id.clone());
+    let status = run_job(&state, &app_config, prepared_job).await;
+    peer_info.write().await.job_id = None;
 
-    if payment_required == true {
-        if let Err(_e) = debt_account(&pow_msg.event.pubkey, pow_msg.target_pow, &pow_msg.event.id).await {
-            // TODO: Need to add logging / record keeping here for financial and troubleshooting
-            send_notice(peer_tx, &format!("pow: out of credit")).await;
-            return Ok(())
-        }
+    match status {
+        JobStatus::Completed { event, difficulty, signed, signer_error } => {
+            if difficulty < pow_msg.target_pow {
+                send_notice(peer_tx.clone(), &format!("pow: mined difficulty {difficulty} of {}", pow_msg.target_pow)).await;
+            }
+            if let Some(signer_error) = signer_error {
+                send_notice(peer_tx.clone(), &format!("pow: remote signing failed: {signer_error}")).await;
+            }
+            // The event, and how it was mined
+            let mining_result = json!({ "difficulty": difficulty, "preserve_created_at": preserve_created_at, "signed": signed });
+            let event_json_str = serde_json::to_string(&event)?;
+            let reply_str = format!(r#"["POW",{},{}]"#, &event_json_str, mining_result);
+            send_msg(peer_tx, &reply_str).await;
+        },
+
+        JobStatus::Failed { message } => send_notice(peer_tx, &format!("pow: {message}")).await,
+
+        _ => send_notice(peer_tx, "pow: request failed").await,
     }
 
-    info!("Generating target POW: {} for {:?}", &pow_msg.target_pow, &pow_msg.event);
+    Ok(())
+}
 
-    match generate_pow(pow_msg.target_pow, pow_msg.event.clone()).await {
-        Err(e) => {
+async fn handle_admin_msg(
+        state: Arc<AppState>,
+        app_config: Arc<AppConfig>,
+        peer_info: Arc<RwLock<PeerInfo>>,
+        admin_msg: AdminCmd,
+        peer_tx: mpsc::Sender<Message>
+    ) -> Result<()> {
 
-            warn!("generate_pow failed. {} {} {} {e:?}", &pow_msg.event.pubkey, pow_msg.target_pow, &pow_msg.event.id);
+    if admin_msg.cmd != "ADMIN" {
+        send_notice(peer_tx, "Unable to parse message").await;
+        return Ok(())
+    }
 
-            if payment_required == true {
-                match credit_account(&pow_msg.event.pubkey, pow_msg.target_pow, &pow_msg.event.id).await {
-                    Ok(_) => {
-                        // TODO: Need to add logging / record keeping here for financial and troubleshooting
-                    },
-                    Err(_) => {
-                        // TODO: Need to add logging / record keeping here for financial and troubleshooting
-                    },
-                }
-            }
+    // Any authenticated admin pubkey. Non-admins are turned away by handle_admin_request
+    let admin_pubkey = {
+        let peer_info = peer_info.read().await;
+        peer_info.pubkeys.iter().find(|pubkey| app_config.is_admin(pubkey)).or(peer_info.pubkeys.first()).cloned()
+    };
 
-            send_notice(peer_tx, &format!("pow: request failed")).await;
+    let admin_pubkey = match admin_pubkey {
+        Some(pubkey) => pubkey,
+        None => {
+            send_notice(peer_tx, "restricted: you need to authorise to confirm your pubkey first").await;
             return Ok(())
         },
+    };
 
-        Ok(event) => {
-            let event_json_str = serde_json::to_string(&event)?;
-            let reply_str = format!(r#"["POW",{}]"#, &event_json_str);
-            send_msg(peer_tx, &reply_str).await;
+    let reply = match handle_admin_request(&state, &app_config, &admin_pubkey, admin_msg.request).await {
+        Ok(result) => json!(["ADMIN", { "ok": true, "result": result }]),
+        Err(e) => json!(["ADMIN", { "ok": false, "error": e.to_string() }]),
+    };
+
+    send_msg(peer_tx, &reply.to_string()).await;
 
+    Ok(())
+}
+
+// Publish a signed event we mined for this pubkey, and report each relay's OK
+async fn handle_publish_msg(
+        state: Arc<AppState>,
+        app_config: Arc<AppConfig>,
+        peer_info: Arc<RwLock<PeerInfo>>,
+        publish_msg: PublishCmd,
+        peer_tx: mpsc::Sender<Message>
+    ) -> Result<()> {
+
+    let authenticated_pubkeys = {
+        let peer_info = peer_info.read().await;
+
+        if !peer_info.is_authenticated() {
+            send_notice(peer_tx, "restricted: you need to authorise to confirm your pubkey first").await;
             return Ok(())
-        },
+        }
+
+        peer_info.pubkeys.clone()
+    };
+
+    let event = publish_msg.event;
+
+    // Only a pubkey that requested the work (which may be the event's delegator) can publish it
+    if state.registry.get(&event.id).map_or(true, |record| !authenticated_pubkeys.contains(&record.pubkey)) {
+        send_notice(peer_tx, "publish: restricted: only events mined for you can be published").await;
+        return Ok(())
+    }
+
+    if event.validate().is_err() {
+        send_notice(peer_tx, "publish: error: invalid event signature").await;
+        return Ok(())
     }
+
+    let mut relays = match publish_msg.relays.is_empty() {
+        true => app_config.default_relays.clone(),
+        false => publish_msg.relays,
+    };
+    let mut seen = std::collections::HashSet::new();
+    relays.retain(|relay| seen.insert(relay.clone()));
+
+    if relays.is_empty() {
+        send_notice(peer_tx, "publish: error: no relays to publish to").await;
+        return Ok(())
+    }
+
+    if relays.len() > app_config.max_publish_relays {
+        send_notice(peer_tx, &format!("publish: restricted: at most {} relays", app_config.max_publish_relays)).await;
+        return Ok(())
+    }
+
+    if let Some(Err(e)) = relays.iter().map(|relay| app_config.relay_access.check_url(relay)).find(Result::is_err) {
+        send_notice(peer_tx, &format!("publish: restricted: {e}")).await;
+        return Ok(())
+    }
+
+    let results = publish_event(&relays, &event, app_config.publish_timeout, &app_config.relay_access).await;
+
+    let reply = json!(["PUBLISH", &event.id, results]);
+    send_msg(peer_tx, &reply.to_string()).await;
+
+    Ok(())
+}
+
+// Anyone connected can check a mined event, so no AUTH is needed
+async fn handle_verify_msg(state: Arc<AppState>, verify_msg: VerifyCmd, peer_tx: mpsc::Sender<Message>) {
+    let verification = state.registry.verify(&verify_msg.event);
+
+    let reply = json!(["VERIFY", &verify_msg.event.id, verification]);
+    send_msg(peer_tx, &reply.to_string()).await;
 }
 
 async fn send_msg(peer_tx: mpsc::Sender<Message>, message: &str) {
@@ -236,7 +526,8 @@ async fn send_msg(peer_tx: mpsc::Sender<Message>, message: &str) {
 }
 
 async fn send_notice(peer_tx: mpsc::Sender<Message>, notice: &str) {
-    let notice_str = format!(r#"["NOTICE","{notice}"]"#);
+    // Notices can carry text from policy plugins, so let serde handle escaping
+    let notice_str = json!(["NOTICE", notice]).to_string();
     let notice_msg = Message::text(notice_str);
 
     if let Err(SendTimeoutError::Closed(e)) = peer_tx.send_timeout(notice_msg, MPSC_SEND_TIMEOUT).await {
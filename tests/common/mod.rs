@@ -0,0 +1,61 @@
+#![allow(dead_code)]
+
+use clap::Parser;
+use nostr_rs_relay::event::Event;
+use nostr_rust::events::EventPrepare;
+use nostrgraph_pow_service::clock::ManualClock;
+use nostrgraph_pow_service::config::{AppArgs, AppConfig, Settings};
+use nostrgraph_pow_service::pow::get_content_id;
+use secp256k1::{KeyPair, Message, Secp256k1};
+use std::sync::Arc;
+
+/// Pubkey for events that are mined but not signed
+pub const PUBKEY: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+/// Config from command line args (and defaults), with the clock fixed at `now`
+pub async fn app_config(args: &[&str], now: u64) -> AppConfig {
+    let args = AppArgs::parse_from(["nostr_pow_service"].iter().chain(args));
+    let settings = Settings::load(&args).expect("settings");
+
+    AppConfig::build(settings, None).await.expect("config").with_clock(Arc::new(ManualClock::new(now)))
+}
+
+/// A fixed test keypair
+pub fn keypair(secret_key: [u8; 32]) -> KeyPair {
+    KeyPair::from_seckey_slice(&Secp256k1::new(), &secret_key).expect("valid secret key")
+}
+
+pub fn pubkey(keypair: &KeyPair) -> String {
+    keypair.x_only_public_key().0.to_string()
+}
+
+/// An event with a valid id and signature
+pub fn signed_event(keypair: &KeyPair, created_at: u64, kind: u64, tags: Vec<Vec<String>>) -> Event {
+    let event_prepare = EventPrepare {
+        pub_key: pubkey(keypair),
+        created_at,
+        kind: kind as u16,
+        tags,
+        content: "test".to_string(),
+    };
+
+    let id = get_content_id(&event_prepare);
+    let message = Message::from_slice(&hex::decode(&id).unwrap()).unwrap();
+    let sig = Secp256k1::new().sign_schnorr_no_aux_rand(&message, keypair);
+
+    Event {
+        id,
+        pubkey: event_prepare.pub_key,
+        delegated_by: None,
+        created_at,
+        kind,
+        tags: event_prepare.tags,
+        content: event_prepare.content,
+        sig: sig.to_string(),
+        tagidx: None,
+    }
+}
+
+pub fn tag(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
@@ -0,0 +1,138 @@
+mod common;
+
+use common::{app_config, keypair, signed_event, tag};
+use nostrgraph_pow_service::auth::check_created_at_window;
+use nostrgraph_pow_service::clock::ManualClock;
+use nostrgraph_pow_service::config::AppConfig;
+use nostrgraph_pow_service::delegation::GrantStore;
+use nostrgraph_pow_service::peer::PeerInfo;
+use nostrgraph_pow_service::pow::validate_pow_request;
+
+const NOW: u64 = 1_700_000_000;
+const SECRET_KEY: [u8; 32] = [1; 32];
+
+#[test]
+fn window_includes_its_edges() {
+    let clock = ManualClock::new(NOW);
+
+    assert!(check_created_at_window(&clock, NOW, 600).is_ok());
+    assert!(check_created_at_window(&clock, NOW - 600, 600).is_ok());
+    assert!(check_created_at_window(&clock, NOW + 600, 600).is_ok());
+}
+
+#[test]
+fn window_excludes_just_outside_its_edges() {
+    let clock = ManualClock::new(NOW);
+
+    assert!(check_created_at_window(&clock, NOW - 601, 600).is_err());
+    assert!(check_created_at_window(&clock, NOW + 601, 600).is_err());
+}
+
+#[test]
+fn window_near_zero_doesnt_underflow() {
+    let clock = ManualClock::new(100);
+
+    assert!(check_created_at_window(&clock, 0, 600).is_ok());
+    assert!(check_created_at_window(&clock, 700, 600).is_ok());
+    assert!(check_created_at_window(&clock, 701, 600).is_err());
+
+    let clock = ManualClock::new(0);
+    assert!(check_created_at_window(&clock, 0, 600).is_ok());
+    assert!(check_created_at_window(&clock, 601, 600).is_err());
+}
+
+#[test]
+fn window_near_max_doesnt_overflow() {
+    let clock = ManualClock::new(u64::MAX - 100);
+
+    assert!(check_created_at_window(&clock, u64::MAX, 600).is_ok());
+    assert!(check_created_at_window(&clock, u64::MAX - 700, 600).is_ok());
+    assert!(check_created_at_window(&clock, u64::MAX - 701, 600).is_err());
+}
+
+#[tokio::test]
+async fn pow_request_created_at_window() {
+    let app_config = app_config(&[], NOW).await;
+    let delta_sec = app_config.created_at_delta_sec;
+    let profile = app_config.default_profile();
+    let difficulty = profile.min_pow_difficulty;
+    let keypair = keypair(SECRET_KEY);
+
+    let validate = |created_at| {
+        let event = signed_event(&keypair, created_at, 1, vec![]);
+        validate_pow_request(&app_config, &GrantStore::default(), profile, difficulty, &event, &event.pubkey)
+    };
+
+    assert!(validate(NOW).is_ok());
+    assert!(validate(NOW - delta_sec).is_ok());
+    assert!(validate(NOW + delta_sec).is_ok());
+    assert!(validate(NOW - delta_sec - 1).is_err());
+    assert!(validate(NOW + delta_sec + 1).is_err());
+}
+
+#[tokio::test]
+async fn pow_request_near_zero_time_doesnt_underflow() {
+    let app_config = app_config(&[], 10).await;
+    let profile = app_config.default_profile();
+    let event = signed_event(&keypair(SECRET_KEY), 0, 1, vec![]);
+
+    assert!(validate_pow_request(&app_config, &GrantStore::default(), profile, profile.min_pow_difficulty, &event, &event.pubkey).is_ok());
+}
+
+#[tokio::test]
+async fn pow_request_rejects_an_invalid_signature() {
+    let app_config = app_config(&[], NOW).await;
+    let profile = app_config.default_profile();
+    let mut event = signed_event(&keypair(SECRET_KEY), NOW, 1, vec![]);
+    event.content = "changed".to_string();
+
+    assert!(validate_pow_request(&app_config, &GrantStore::default(), profile, profile.min_pow_difficulty, &event, &event.pubkey).is_err());
+}
+
+fn auth_event(app_config: &AppConfig, peer_info: &PeerInfo, created_at: u64) -> nostr_rs_relay::event::Event {
+    let tags = vec![
+        tag(&["relay", &app_config.relay_identifier]),
+        tag(&["challenge", &peer_info.auth_challenge]),
+    ];
+
+    signed_event(&keypair(SECRET_KEY), created_at, 22242, tags)
+}
+
+#[tokio::test]
+async fn auth_response_created_at_window() {
+    let app_config = app_config(&[], NOW).await;
+    let delta_sec = app_config.auth_created_at_delta_sec;
+
+    for (created_at, accepted) in [
+        (NOW, true),
+        (NOW - delta_sec, true),
+        (NOW + delta_sec, true),
+        (NOW - delta_sec - 1, false),
+        (NOW + delta_sec + 1, false),
+    ] {
+        let mut peer_info = PeerInfo::new(1, None);
+        let event = auth_event(&app_config, &peer_info, created_at);
+
+        assert_eq!(peer_info.check_auth_response(&app_config, &event).is_ok(), accepted, "created_at {created_at}");
+        assert_eq!(peer_info.is_authenticated(), accepted);
+    }
+}
+
+#[tokio::test]
+async fn auth_response_near_zero_time_doesnt_underflow() {
+    let app_config = app_config(&[], 10).await;
+    let mut peer_info = PeerInfo::new(1, None);
+    let event = auth_event(&app_config, &peer_info, 0);
+
+    assert!(peer_info.check_auth_response(&app_config, &event).is_ok());
+}
+
+#[tokio::test]
+async fn auth_response_rejects_another_challenge() {
+    let app_config = app_config(&[], NOW).await;
+    let mut peer_info = PeerInfo::new(1, None);
+    let event = auth_event(&app_config, &PeerInfo::new(2, None), NOW);
+
+    assert!(peer_info.check_auth_response(&app_config, &event).is_err());
+    assert!(!peer_info.is_authenticated());
+}
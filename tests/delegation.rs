@@ -0,0 +1,174 @@
+mod common;
+
+use common::{app_config, keypair, pubkey, signed_event, tag};
+use nostr_rs_relay::event::Event;
+use nostrgraph_pow_service::delegation::{delegator, GrantStore};
+use nostrgraph_pow_service::pow::validate_pow_request;
+
+// NIP-26 test vector
+const DELEGATOR_PUBKEY: &str = "8e0d3d3eb2881ec137a11debe736a9086715a8c8beeeda615780064d68bc25dd";
+const DELEGATEE_SECRET_KEY: &str = "777e4f60b4aa87937e13acc84f7abcc3c93cc035cb4c1e9f7a9086dd78fffce1";
+const DELEGATEE_PUBKEY: &str = "477318cfb5427b9cfc66a9fa376150c1ddbc62115ae27cef72417eb959691396";
+const CONDITIONS: &str = "kind=1&created_at>1674834236&created_at<1677426236";
+const TOKEN: &str = "6f44d7fe4f1c09f3954640fb58bd12bae8bb8ff4120853c4693106c82e920e2b898f1f9ba9bd65449a987c39c0423426ab7b53910c0c6abfb41b30bc16e5f524";
+
+// Within the delegation's created_at conditions
+const CREATED_AT: u64 = 1_676_000_000;
+
+fn delegation_tag() -> Vec<String> {
+    tag(&["delegation", DELEGATOR_PUBKEY, CONDITIONS, TOKEN])
+}
+
+fn event(pubkey: &str, created_at: u64, kind: u64, tags: Vec<Vec<String>>) -> Event {
+    Event {
+        id: "0".to_owned(),
+        pubkey: pubkey.to_owned(),
+        delegated_by: None,
+        created_at,
+        kind,
+        tags,
+        content: "delegation test".to_owned(),
+        sig: "0".to_owned(),
+        tagidx: None,
+    }
+}
+
+#[test]
+fn spec_test_vector_is_valid() {
+    let event = event(DELEGATEE_PUBKEY, CREATED_AT, 1, vec![delegation_tag()]);
+
+    assert_eq!(delegator(&event).unwrap(), Some(DELEGATOR_PUBKEY.to_string()));
+}
+
+#[test]
+fn no_delegation_tag_has_no_delegator() {
+    let event = event(DELEGATEE_PUBKEY, CREATED_AT, 1, vec![tag(&["t", "pow"])]);
+
+    assert_eq!(delegator(&event).unwrap(), None);
+}
+
+#[test]
+fn token_for_another_delegatee_is_rejected() {
+    let other_pubkey = pubkey(&keypair([2; 32]));
+    let event = event(&other_pubkey, CREATED_AT, 1, vec![delegation_tag()]);
+
+    assert!(delegator(&event).is_err());
+}
+
+#[test]
+fn changed_conditions_are_rejected() {
+    let conditions = "kind=1&created_at>1674834236&created_at<1777426236";
+    let tags = vec![tag(&["delegation", DELEGATOR_PUBKEY, conditions, TOKEN])];
+
+    assert!(delegator(&event(DELEGATEE_PUBKEY, CREATED_AT, 1, tags)).is_err());
+}
+
+#[test]
+fn expired_delegation_is_rejected() {
+    let event = event(DELEGATEE_PUBKEY, 1_677_426_236, 1, vec![delegation_tag()]);
+
+    assert_eq!(delegator(&event).unwrap_err().to_string(), "delegation expired");
+}
+
+#[test]
+fn not_yet_valid_delegation_is_rejected() {
+    let event = event(DELEGATEE_PUBKEY, 1_674_834_236, 1, vec![delegation_tag()]);
+
+    assert_eq!(delegator(&event).unwrap_err().to_string(), "delegation not yet valid");
+}
+
+#[test]
+fn kind_not_allowed_is_rejected() {
+    let event = event(DELEGATEE_PUBKEY, CREATED_AT, 7, vec![delegation_tag()]);
+
+    assert_eq!(delegator(&event).unwrap_err().to_string(), "delegation doesn't allow kind 7");
+}
+
+#[test]
+fn malformed_delegation_tag_is_rejected() {
+    let event = event(DELEGATEE_PUBKEY, CREATED_AT, 1, vec![tag(&["delegation", DELEGATOR_PUBKEY, CONDITIONS])]);
+
+    assert!(delegator(&event).is_err());
+}
+
+#[tokio::test]
+async fn delegated_request_needs_delegation_enabled() {
+    let delegatee = keypair(hex::decode(DELEGATEE_SECRET_KEY).unwrap().try_into().unwrap());
+    let event = signed_event(&delegatee, CREATED_AT, 1, vec![delegation_tag()]);
+    let grants = GrantStore::default();
+
+    let disabled = app_config(&[], CREATED_AT).await;
+    let profile = disabled.default_profile();
+    assert!(validate_pow_request(&disabled, &grants, profile, profile.min_pow_difficulty, &event, DELEGATOR_PUBKEY).is_err());
+
+    let enabled = app_config(&["--allow-delegated-requests"], CREATED_AT).await;
+    let profile = enabled.default_profile();
+    assert!(validate_pow_request(&enabled, &grants, profile, profile.min_pow_difficulty, &event, DELEGATOR_PUBKEY).is_ok());
+
+    // Only for the delegator
+    let other_pubkey = pubkey(&keypair([2; 32]));
+    assert!(validate_pow_request(&enabled, &grants, profile, profile.min_pow_difficulty, &event, &other_pubkey).is_err());
+}
+
+#[tokio::test]
+async fn granted_request_is_allowed_until_revoked() {
+    let grantee = keypair([3; 32]);
+    let event = signed_event(&grantee, CREATED_AT, 1, vec![]);
+    let grants = GrantStore::default();
+
+    let app_config = app_config(&["--allow-delegated-requests"], CREATED_AT).await;
+    let profile = app_config.default_profile();
+    let validate = |grants: &GrantStore| {
+        validate_pow_request(&app_config, grants, profile, profile.min_pow_difficulty, &event, DELEGATOR_PUBKEY)
+    };
+
+    assert!(validate(&grants).is_err());
+
+    grants.grant(DELEGATOR_PUBKEY, &event.pubkey).unwrap();
+    assert!(validate(&grants).is_ok());
+
+    assert!(grants.revoke(DELEGATOR_PUBKEY, &event.pubkey).unwrap());
+    assert!(validate(&grants).is_err());
+}
+
+#[test]
+fn grant_store_grants_and_revokes() {
+    let grants = GrantStore::default();
+
+    grants.grant(DELEGATOR_PUBKEY, &DELEGATEE_PUBKEY.to_uppercase()).unwrap();
+    assert!(grants.is_granted(DELEGATOR_PUBKEY, DELEGATEE_PUBKEY));
+    assert!(!grants.is_granted(DELEGATEE_PUBKEY, DELEGATOR_PUBKEY));
+    assert_eq!(grants.list(DELEGATOR_PUBKEY), vec![DELEGATEE_PUBKEY.to_string()]);
+
+    assert!(grants.revoke(DELEGATOR_PUBKEY, &DELEGATEE_PUBKEY.to_uppercase()).unwrap());
+    assert!(!grants.is_granted(DELEGATOR_PUBKEY, DELEGATEE_PUBKEY));
+    assert!(grants.list(DELEGATOR_PUBKEY).is_empty());
+
+    // Nothing left to revoke
+    assert!(!grants.revoke(DELEGATOR_PUBKEY, DELEGATEE_PUBKEY).unwrap());
+}
+
+#[test]
+fn grant_store_rejects_an_invalid_pubkey() {
+    let grants = GrantStore::default();
+
+    assert!(grants.grant(DELEGATOR_PUBKEY, "not a pubkey").is_err());
+    assert!(grants.list(DELEGATOR_PUBKEY).is_empty());
+}
+
+#[test]
+fn grant_store_keeps_grants_in_its_file() {
+    let path = std::env::temp_dir().join(format!("nostr_pow_service_grants_{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let grants = GrantStore::open(Some(&path)).unwrap();
+    grants.grant(DELEGATOR_PUBKEY, DELEGATEE_PUBKEY).unwrap();
+
+    let reopened = GrantStore::open(Some(&path)).unwrap();
+    assert!(reopened.is_granted(DELEGATOR_PUBKEY, DELEGATEE_PUBKEY));
+
+    reopened.revoke(DELEGATOR_PUBKEY, DELEGATEE_PUBKEY).unwrap();
+    assert!(!GrantStore::open(Some(&path)).unwrap().is_granted(DELEGATOR_PUBKEY, DELEGATEE_PUBKEY));
+
+    std::fs::remove_file(&path).unwrap();
+}
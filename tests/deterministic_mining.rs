@@ -0,0 +1,85 @@
+mod common;
+
+use common::PUBKEY;
+use nostr_rust::events::EventPrepare;
+use nostrgraph_pow_service::clock::{Clock, ManualClock};
+use nostrgraph_pow_service::pow::{
+    generate_pow_event, get_content_id, MiningControl, MiningOptions, NonceStrategy,
+};
+use std::sync::Arc;
+
+const NOW: u64 = 1_700_000_000;
+
+fn event() -> EventPrepare {
+    EventPrepare {
+        pub_key: PUBKEY.to_string(),
+        created_at: NOW - 1000,
+        kind: 1,
+        tags: vec![],
+        content: "deterministic mining test".to_string(),
+    }
+}
+
+fn mine(nonce_strategy: NonceStrategy, preserve_created_at: bool) -> (String, EventPrepare) {
+    let options = MiningOptions {
+        nonce_strategy,
+        preserve_created_at,
+        clock: Arc::new(ManualClock::new(NOW)),
+        ..Default::default()
+    };
+
+    let (id, mined, _) = generate_pow_event(event(), 8, &MiningControl::default(), &options).expect("mined");
+    assert_eq!(id, get_content_id(&mined));
+
+    (id, mined)
+}
+
+#[test]
+fn sequential_nonces_mine_an_exact_id() {
+    let (id, mined) = mine(NonceStrategy::Sequential { offset: 0 }, false);
+
+    assert_eq!(id, "00652d17a98f1cf4bc82245f868c4d2ee77a173fdf805aa5d3a2efd3e460e8df");
+    assert_eq!(mined.created_at, NOW);
+    assert_eq!(mined.tags, vec![vec!["nonce".to_string(), "221".to_string(), "8".to_string()]]);
+}
+
+#[test]
+fn sequential_nonces_start_at_the_offset() {
+    let (id, mined) = mine(NonceStrategy::Sequential { offset: 221 }, false);
+
+    assert_eq!(id, "00652d17a98f1cf4bc82245f868c4d2ee77a173fdf805aa5d3a2efd3e460e8df");
+    assert_eq!(mined.tags[0][1], "221");
+}
+
+#[test]
+fn seeded_nonces_are_reproducible() {
+    let (first_id, _) = mine(NonceStrategy::Seeded { seed: 42 }, false);
+    let (second_id, _) = mine(NonceStrategy::Seeded { seed: 42 }, false);
+    let (other_seed_id, _) = mine(NonceStrategy::Seeded { seed: 43 }, false);
+
+    assert_eq!(first_id, second_id);
+    assert_ne!(first_id, other_seed_id);
+}
+
+#[test]
+fn created_at_comes_from_the_clock() {
+    let clock = Arc::new(ManualClock::new(NOW));
+    clock.advance(30);
+
+    let options = MiningOptions {
+        nonce_strategy: NonceStrategy::Sequential { offset: 0 },
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    let (_, mined, _) = generate_pow_event(event(), 4, &MiningControl::default(), &options).expect("mined");
+
+    assert_eq!(mined.created_at, clock.now());
+    assert_eq!(mined.created_at, NOW + 30);
+}
+
+#[test]
+fn preserved_created_at_ignores_the_clock() {
+    let (_, mined) = mine(NonceStrategy::Seeded { seed: 1 }, true);
+
+    assert_eq!(mined.created_at, NOW - 1000);
+}
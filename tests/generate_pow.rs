@@ -0,0 +1,54 @@
+mod common;
+
+use common::{tag, PUBKEY};
+use nostr_rs_relay::event::Event;
+use nostr_rust::events::EventPrepare;
+use nostrgraph_pow_service::pow::{
+    count_leading_zero_bits, generate_pow, get_content_id, MiningControl, MiningOptions,
+};
+use std::sync::Arc;
+
+const CREATED_AT: u64 = 1_700_000_000;
+
+fn event() -> Event {
+    Event {
+        id: "0".to_owned(),
+        pubkey: PUBKEY.to_owned(),
+        delegated_by: None,
+        created_at: CREATED_AT,
+        kind: 1,
+        tags: vec![tag(&["t", "pow"])],
+        content: "generate_pow test".to_owned(),
+        sig: "0".to_owned(),
+        tagidx: None,
+    }
+}
+
+#[tokio::test]
+async fn mined_event_matches_its_id() {
+    let mined = generate_pow(8, event(), Arc::new(MiningControl::default()), MiningOptions::default()).await.expect("mined");
+
+    // The miner bumps created_at, so the returned event must carry the mined created_at
+    // and nonce tag for its id to verify
+    let event_prepare = EventPrepare {
+        pub_key: mined.pubkey.clone(),
+        created_at: mined.created_at,
+        kind: mined.kind as u16,
+        tags: mined.tags.clone(),
+        content: mined.content.clone(),
+    };
+
+    assert_eq!(mined.id, get_content_id(&event_prepare));
+    assert!(count_leading_zero_bits(hex::decode(&mined.id).unwrap()) >= 8);
+    assert_ne!(mined.created_at, CREATED_AT);
+}
+
+#[tokio::test]
+async fn mined_event_keeps_its_tags_and_adds_one_nonce_tag() {
+    let mined = generate_pow(8, event(), Arc::new(MiningControl::default()), MiningOptions::default()).await.expect("mined");
+
+    assert_eq!(mined.tags.len(), 2);
+    assert_eq!(mined.tags[0], tag(&["t", "pow"]));
+    assert_eq!(mined.tags[1][0], "nonce");
+    assert_eq!(mined.tags[1][2], "8");
+}
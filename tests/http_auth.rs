@@ -0,0 +1,72 @@
+mod common;
+
+use base64::Engine;
+use common::{app_config, keypair, signed_event, tag};
+use nostrgraph_pow_service::auth::{verify_http_auth, HttpAuthRequest, SeenAuthEvents, HTTP_AUTH_KIND};
+
+const NOW: u64 = 1_700_000_000;
+const URL: &str = "https://pow.example.com/pow";
+const BODY: &[u8] = br#"{"event": {}, "target_pow": 20}"#;
+
+fn authorization(tags: Vec<Vec<String>>) -> String {
+    let event = signed_event(&keypair([1; 32]), NOW, HTTP_AUTH_KIND, tags);
+    format!("Nostr {}", base64::engine::general_purpose::STANDARD.encode(serde_json::to_string(&event).unwrap()))
+}
+
+fn post_tags(payload: Option<&str>) -> Vec<Vec<String>> {
+    let mut tags = vec![tag(&["u", URL]), tag(&["method", "POST"])];
+    tags.extend(payload.map(|payload| tag(&["payload", payload])));
+    tags
+}
+
+fn post<'a>(urls: &'a [String], seen_events: &'a SeenAuthEvents) -> HttpAuthRequest<'a> {
+    HttpAuthRequest { urls, method: "POST", body: Some(BODY), payload_required: true, seen_events: Some(seen_events) }
+}
+
+#[tokio::test]
+async fn body_needs_a_matching_payload_tag() {
+    let app_config = app_config(&[], NOW).await;
+    let urls = [URL.to_string()];
+    let seen_events = SeenAuthEvents::default();
+
+    let error = verify_http_auth(&app_config, &authorization(post_tags(None)), &post(&urls, &seen_events)).unwrap_err();
+    assert_eq!(error.to_string(), "Missing payload tag");
+
+    let other_body_hash = sha256::digest(&b"{}"[..]);
+    let error = verify_http_auth(&app_config, &authorization(post_tags(Some(&other_body_hash))), &post(&urls, &seen_events)).unwrap_err();
+    assert_eq!(error.to_string(), "Invalid payload tag");
+
+    let body_hash = sha256::digest(BODY);
+    assert!(verify_http_auth(&app_config, &authorization(post_tags(Some(&body_hash))), &post(&urls, &seen_events)).is_ok());
+}
+
+#[tokio::test]
+async fn payload_tag_is_optional_without_a_body() {
+    let app_config = app_config(&[], NOW).await;
+    let urls = [URL.to_string()];
+    let request = HttpAuthRequest { urls: &urls, method: "GET", body: None, payload_required: false, seen_events: None };
+
+    assert!(verify_http_auth(&app_config, &authorization(vec![tag(&["u", URL]), tag(&["method", "GET"])]), &request).is_ok());
+}
+
+#[tokio::test]
+async fn auth_event_cant_be_replayed() {
+    let app_config = app_config(&[], NOW).await;
+    let urls = [URL.to_string()];
+    let seen_events = SeenAuthEvents::default();
+    let authorization = authorization(post_tags(Some(&sha256::digest(BODY))));
+
+    assert!(verify_http_auth(&app_config, &authorization, &post(&urls, &seen_events)).is_ok());
+
+    let error = verify_http_auth(&app_config, &authorization, &post(&urls, &seen_events)).unwrap_err();
+    assert_eq!(error.to_string(), "Authorization event already used");
+}
+
+#[test]
+fn seen_events_are_forgotten_once_expired() {
+    let seen_events = SeenAuthEvents::default();
+
+    assert!(seen_events.insert("a", NOW + 60, NOW).is_ok());
+    assert!(seen_events.insert("a", NOW + 60, NOW + 60).is_err());
+    assert!(seen_events.insert("a", NOW + 120, NOW + 61).is_ok());
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use common::{tag, PUBKEY};
+use nostr_rust::events::EventPrepare;
+use nostrgraph_pow_service::pow::{
+    count_leading_zero_bits, generate_pow_event, get_content_id, set_nonce_tag, MiningControl,
+    MiningOptions, NonceTagPosition,
+};
+
+fn event_with_tags(tags: Vec<Vec<String>>) -> EventPrepare {
+    EventPrepare {
+        pub_key: PUBKEY.to_string(),
+        created_at: 1_700_000_000,
+        kind: 1,
+        tags,
+        content: "nonce tag test".to_string(),
+    }
+}
+
+fn mine(tags: Vec<Vec<String>>, nonce_tag_position: NonceTagPosition) -> EventPrepare {
+    let options = MiningOptions { nonce_tag_position, ..Default::default() };
+    let (id, mined, leading_zeros) = generate_pow_event(event_with_tags(tags), 8, &MiningControl::default(), &options)
+        .expect("mined");
+
+    assert_eq!(id, get_content_id(&mined));
+    assert!(leading_zeros >= 8);
+    assert_eq!(count_leading_zero_bits(hex::decode(&id).unwrap()), leading_zeros);
+
+    mined
+}
+
+fn nonce_tags(event: &EventPrepare) -> Vec<&Vec<String>> {
+    event.tags.iter().filter(|tag| tag[0] == "nonce").collect()
+}
+
+#[test]
+fn replaces_existing_nonce_tag() {
+    let tags = vec![tag(&["e", "abc"]), tag(&["nonce", "12345", "4"]), tag(&["p", PUBKEY])];
+
+    let mined = mine(tags, NonceTagPosition::Last);
+
+    assert_eq!(nonce_tags(&mined).len(), 1);
+    assert_eq!(mined.tags.len(), 3);
+    assert_eq!(mined.tags[0], tag(&["e", "abc"]));
+    assert_eq!(mined.tags[1], tag(&["p", PUBKEY]));
+    assert_eq!(mined.tags[2][0], "nonce");
+    assert_eq!(mined.tags[2][2], "8");
+}
+
+#[test]
+fn replaces_every_existing_nonce_tag() {
+    let tags = vec![tag(&["nonce", "1", "4"]), tag(&["t", "pow"]), tag(&["nonce", "2"])];
+
+    let mined = mine(tags, NonceTagPosition::Last);
+
+    assert_eq!(nonce_tags(&mined).len(), 1);
+    assert_eq!(mined.tags[0], tag(&["t", "pow"]));
+    assert_eq!(mined.tags[1][0], "nonce");
+}
+
+#[test]
+fn nonce_tag_first() {
+    let tags = vec![tag(&["e", "abc"]), tag(&["p", PUBKEY]), tag(&["nonce", "12345", "4"])];
+
+    let mined = mine(tags, NonceTagPosition::First);
+
+    assert_eq!(nonce_tags(&mined).len(), 1);
+    assert_eq!(mined.tags[0][0], "nonce");
+    assert_eq!(mined.tags[1], tag(&["e", "abc"]));
+    assert_eq!(mined.tags[2], tag(&["p", PUBKEY]));
+}
+
+#[test]
+fn adds_nonce_tag_without_existing_tags() {
+    let mined = mine(vec![], NonceTagPosition::Last);
+
+    assert_eq!(mined.tags.len(), 1);
+    assert_eq!(mined.tags[0][0], "nonce");
+}
+
+#[test]
+fn set_nonce_tag_returns_index() {
+    let mut tags = vec![tag(&["e", "abc"]), tag(&["nonce", "1", "4"]), tag(&["p", PUBKEY])];
+
+    let index = set_nonce_tag(&mut tags, tag(&["nonce", "2", "8"]), NonceTagPosition::Last);
+
+    assert_eq!(index, 2);
+    assert_eq!(tags, vec![tag(&["e", "abc"]), tag(&["p", PUBKEY]), tag(&["nonce", "2", "8"])]);
+}
@@ -0,0 +1,60 @@
+use nostrgraph_pow_service::relay::{is_public_ip, RelayAccess};
+use std::net::IpAddr;
+
+fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+}
+
+#[test]
+fn public_addresses_are_allowed() {
+    assert!(is_public_ip(ip("1.1.1.1")));
+    assert!(is_public_ip(ip("2606:4700:4700::1111")));
+}
+
+#[test]
+fn special_purpose_addresses_are_refused() {
+    for address in [
+        "0.0.0.0", "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254",
+        "100.64.0.1", "198.18.0.1", "224.0.0.1", "255.255.255.255",
+        "::", "::1", "fd00::1", "fe80::1", "ff02::1", "::ffff:127.0.0.1", "::ffff:10.0.0.1",
+    ] {
+        assert!(!is_public_ip(ip(address)), "{address}");
+    }
+}
+
+#[test]
+fn relays_must_use_wss_without_an_allowlist() {
+    let relay_access = RelayAccess::default();
+
+    assert!(relay_access.check_url("wss://relay.example.com").is_ok());
+
+    let error = relay_access.check_url("ws://relay.example.com").unwrap_err();
+    assert_eq!(error.to_string(), "relay url must use wss://");
+
+    assert!(relay_access.check_url("https://relay.example.com").is_err());
+    assert!(relay_access.check_url("not a url").is_err());
+}
+
+#[test]
+fn allowlist_limits_relay_hosts() {
+    let relay_access = RelayAccess { allowlist: vec!["relay.internal".to_string()] };
+
+    let (_, allowlisted) = relay_access.check_url("ws://relay.internal:7000").unwrap();
+    assert!(allowlisted);
+
+    let error = relay_access.check_url("wss://relay.example.com").unwrap_err();
+    assert_eq!(error.to_string(), "relay relay.example.com isn't allowed");
+}
+
+#[tokio::test]
+async fn private_relays_are_refused_after_resolving() {
+    let relay_access = RelayAccess::default();
+
+    for relay_url in ["wss://127.0.0.1", "wss://[::1]:7000", "wss://localhost"] {
+        let error = relay_access.resolve(relay_url).await.unwrap_err();
+        assert!(error.to_string().ends_with("isn't on a public address"), "{relay_url}: {error}");
+    }
+
+    let relay_access = RelayAccess { allowlist: vec!["127.0.0.1".to_string()] };
+    assert!(!relay_access.resolve("ws://127.0.0.1:7000").await.unwrap().is_empty());
+}
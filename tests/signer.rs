@@ -0,0 +1,130 @@
+mod common;
+
+use base64::Engine;
+use common::PUBKEY as REMOTE_PUBKEY;
+use nostrgraph_pow_service::signer::{
+    nip04_decrypt, nip04_encrypt, nip04_shared_key, nip44_conversation_key, nip44_decrypt, nip44_encrypt, BunkerUri,
+};
+use secp256k1::{KeyPair, Secp256k1, SecretKey, XOnlyPublicKey};
+
+fn keys(secret: u8) -> (SecretKey, XOnlyPublicKey) {
+    let mut secret_key = [0u8; 32];
+    secret_key[31] = secret;
+    let secret_key = SecretKey::from_slice(&secret_key).unwrap();
+    let (pubkey, _) = KeyPair::from_secret_key(&Secp256k1::new(), &secret_key).x_only_public_key();
+    (secret_key, pubkey)
+}
+
+#[test]
+fn bunker_uri_parses_relays_and_secret() {
+    let uri = format!("bunker://{}?relay=wss%3A%2F%2Frelay.example.com&relay=wss://relay2.example.com&secret=s3cret", REMOTE_PUBKEY.to_uppercase());
+    let bunker: BunkerUri = uri.parse().unwrap();
+
+    assert_eq!(bunker.remote_pubkey, REMOTE_PUBKEY);
+    assert_eq!(bunker.relays, vec!["wss://relay.example.com", "wss://relay2.example.com"]);
+
+    // The secret is kept, but never shown in logs
+    let reparsed: BunkerUri = String::from(bunker.clone()).parse().unwrap();
+    assert_eq!(reparsed, bunker);
+    assert!(String::from(bunker.clone()).contains("secret=s3cret"));
+    assert!(!format!("{bunker:?}").contains("s3cret"));
+}
+
+#[test]
+fn bunker_uri_rejects_invalid_uris() {
+    for (uri, error) in [
+        ("not a uri".to_string(), "invalid bunker uri"),
+        (format!("nostrconnect://{REMOTE_PUBKEY}?relay=wss://relay.example.com"), "bunker uri must start with bunker://"),
+        ("bunker://abcd?relay=wss://relay.example.com".to_string(), "bunker uri has an invalid remote signer pubkey"),
+        (format!("bunker://{REMOTE_PUBKEY}"), "bunker uri needs at least one relay"),
+        (format!("bunker://{REMOTE_PUBKEY}?relay=https://relay.example.com"), "bunker uri has an invalid relay url https://relay.example.com"),
+    ] {
+        assert_eq!(uri.parse::<BunkerUri>().unwrap_err().to_string(), error, "{uri}");
+    }
+}
+
+#[test]
+fn bunker_uri_deserializes_from_a_string() {
+    let json = format!("\"bunker://{REMOTE_PUBKEY}?relay=wss://relay.example.com\"");
+    let bunker: BunkerUri = serde_json::from_str(&json).unwrap();
+    assert_eq!(bunker.relays, vec!["wss://relay.example.com"]);
+
+    assert!(serde_json::from_str::<BunkerUri>("\"bunker://abcd\"").is_err());
+}
+
+#[test]
+fn nip04_round_trip() {
+    let (client_secret, client_pubkey) = keys(1);
+    let (signer_secret, signer_pubkey) = keys(2);
+
+    let client_key = nip04_shared_key(&client_secret, &signer_pubkey).unwrap();
+    assert_eq!(client_key, nip04_shared_key(&signer_secret, &client_pubkey).unwrap());
+
+    let plaintext = r#"{"id":"1","method":"sign_event","params":["{}"]}"#;
+    let content = nip04_encrypt(&client_key, plaintext);
+
+    assert!(content.contains("?iv="));
+    assert_eq!(nip04_decrypt(&client_key, &content).unwrap(), plaintext);
+    assert!(nip04_decrypt(&client_key, "no iv").is_err());
+}
+
+#[test]
+fn nip44_conversation_key_matches_the_spec_vector() {
+    let (secret_key, _) = keys(1);
+    let (_, pubkey) = keys(2);
+
+    let conversation_key = nip44_conversation_key(&secret_key, &pubkey).unwrap();
+    assert_eq!(hex::encode(conversation_key), "c41c775356fd92eadc63ff5a0dc1da211b268cbea22316767095b2871ea1412d");
+}
+
+#[test]
+fn nip44_decrypts_the_spec_vector() {
+    let (secret_key, _) = keys(1);
+    let (_, pubkey) = keys(2);
+    let conversation_key = nip44_conversation_key(&secret_key, &pubkey).unwrap();
+
+    let payload = "AgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABee0G5VSK0/9YypIObAtDKfYEAjD35uVkHyB0F4DwrcNaCXlCWZKaArsGrY6M9wnuTMxWfp1RTN9Xga8no+kF5Vsb";
+    assert_eq!(nip44_decrypt(&conversation_key, payload).unwrap(), "a");
+}
+
+#[test]
+fn nip44_round_trip() {
+    let (client_secret, client_pubkey) = keys(1);
+    let (signer_secret, signer_pubkey) = keys(2);
+
+    let client_key = nip44_conversation_key(&client_secret, &signer_pubkey).unwrap();
+    let signer_key = nip44_conversation_key(&signer_secret, &client_pubkey).unwrap();
+    assert_eq!(client_key, signer_key);
+
+    // Padded to 32 bytes, then to powers of two (or eighths of them)
+    let base64 = base64::engine::general_purpose::STANDARD;
+    for (len, padded_len) in [(1, 32), (32, 32), (33, 64), (100, 128), (320, 320), (515, 640), (65535, 65536)] {
+        let plaintext = "x".repeat(len);
+        let payload = nip44_encrypt(&client_key, &plaintext).unwrap();
+
+        assert_eq!(base64.decode(&payload).unwrap().len(), 1 + 32 + 2 + padded_len + 32, "{len}");
+        assert_eq!(nip44_decrypt(&signer_key, &payload).unwrap(), plaintext);
+    }
+
+    assert!(nip44_encrypt(&client_key, "").is_err());
+}
+
+#[test]
+fn nip44_rejects_tampered_payloads() {
+    let (secret_key, _) = keys(1);
+    let (_, pubkey) = keys(2);
+    let conversation_key = nip44_conversation_key(&secret_key, &pubkey).unwrap();
+
+    let base64 = base64::engine::general_purpose::STANDARD;
+    let mut payload = base64.decode(nip44_encrypt(&conversation_key, "hello").unwrap()).unwrap();
+    payload[40] ^= 1;
+
+    let error = nip44_decrypt(&conversation_key, &base64.encode(&payload)).unwrap_err();
+    assert_eq!(error.to_string(), "invalid mac");
+
+    let (other_secret, _) = keys(3);
+    let other_key = nip44_conversation_key(&other_secret, &pubkey).unwrap();
+    assert!(nip44_decrypt(&other_key, &nip44_encrypt(&conversation_key, "hello").unwrap()).is_err());
+
+    assert!(nip44_decrypt(&conversation_key, "#unsupported").is_err());
+}
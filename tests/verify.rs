@@ -0,0 +1,138 @@
+mod common;
+
+use common::{tag, PUBKEY};
+use nostr_rs_relay::event::Event;
+use nostr_rust::events::EventPrepare;
+use nostrgraph_pow_service::pow::{
+    count_leading_zero_bits, generate_pow_event, get_content_id, verify, MiningControl, MiningOptions,
+};
+use nostrgraph_pow_service::registry::{MinedRecord, MinedRegistry};
+
+fn event_prepare(tags: Vec<Vec<String>>) -> EventPrepare {
+    EventPrepare {
+        pub_key: PUBKEY.to_string(),
+        created_at: 1_700_000_000,
+        kind: 1,
+        tags,
+        content: "verify test".to_string(),
+    }
+}
+
+// An unsigned event with the id computed from its content (verify doesn't check signatures)
+fn event(event_prepare: EventPrepare) -> Event {
+    Event {
+        id: get_content_id(&event_prepare),
+        pubkey: event_prepare.pub_key,
+        delegated_by: None,
+        created_at: event_prepare.created_at,
+        kind: event_prepare.kind as u64,
+        tags: event_prepare.tags,
+        content: event_prepare.content,
+        sig: String::new(),
+        tagidx: None,
+    }
+}
+
+fn mined_event(difficulty: u16) -> Event {
+    let (_, mined, _) = generate_pow_event(event_prepare(vec![]), difficulty, &MiningControl::default(), &MiningOptions::default())
+        .expect("mined");
+
+    event(mined)
+}
+
+// An event whose nonce tag commits to `committed`, searched for an id with at least `min_leading_zeros`
+fn committed_event(committed: u16, min_leading_zeros: u16) -> Event {
+    (0u64..)
+        .map(|nonce| event(event_prepare(vec![tag(&["nonce", &nonce.to_string(), &committed.to_string()])])))
+        .find(|event| count_leading_zero_bits(hex::decode(&event.id).unwrap()) >= min_leading_zeros)
+        .expect("found")
+}
+
+#[test]
+fn mined_event_is_valid() {
+    let verification = verify(&mined_event(8));
+
+    assert!(verification.id_matches);
+    assert!(verification.nonce_tag);
+    assert_eq!(verification.committed_difficulty, Some(8));
+    assert!(verification.difficulty >= 8);
+    assert!(verification.is_valid(8));
+    assert!(!verification.is_valid(verification.difficulty + 1));
+}
+
+#[test]
+fn id_mismatch_is_invalid() {
+    let mut event = mined_event(8);
+    event.content = "changed after mining".to_string();
+
+    let verification = verify(&event);
+
+    assert!(!verification.id_matches);
+    assert_eq!(verification.committed_difficulty, Some(8));
+    assert!(!verification.is_valid(0));
+}
+
+#[test]
+fn committed_above_actual_difficulty() {
+    let event = committed_event(40, 0);
+    let verification = verify(&event);
+
+    assert!(verification.id_matches);
+    assert_eq!(verification.committed_difficulty, Some(40));
+    assert!(verification.difficulty < 40);
+
+    // Only valid up to the difficulty actually mined
+    assert!(verification.is_valid(verification.difficulty));
+    assert!(!verification.is_valid(40));
+}
+
+#[test]
+fn committed_below_actual_difficulty() {
+    let event = committed_event(2, 8);
+    let verification = verify(&event);
+
+    assert!(verification.id_matches);
+    assert_eq!(verification.committed_difficulty, Some(2));
+    assert!(verification.difficulty >= 8);
+
+    // A lucky hash doesn't count beyond the committed target
+    assert!(verification.is_valid(2));
+    assert!(!verification.is_valid(8));
+}
+
+#[test]
+fn missing_nonce_tag() {
+    let event = event(event_prepare(vec![tag(&["t", "pow"])]));
+    let verification = verify(&event);
+
+    assert!(verification.id_matches);
+    assert!(!verification.nonce_tag);
+    assert_eq!(verification.committed_difficulty, None);
+    assert!(!verification.is_valid(0));
+}
+
+#[tokio::test]
+async fn mined_here() {
+    let registry = MinedRegistry::default();
+    let mined = mined_event(4);
+    let other = committed_event(4, 4);
+
+    registry.insert(MinedRecord {
+        event_id: mined.id.clone(),
+        source_event_id: "source".to_string(),
+        pubkey: PUBKEY.to_string(),
+        difficulty: 4,
+        price_sat: 0,
+        created_at: 1_700_000_000,
+        job_id: "job".to_string(),
+        event: mined.clone(),
+    }).await;
+
+    let verification = registry.verify(&mined);
+    assert!(verification.mined_here);
+    assert_eq!(verification.verification, verify(&mined));
+
+    let verification = registry.verify(&other);
+    assert!(!verification.mined_here);
+    assert!(verification.verification.is_valid(4));
+}